@@ -1,98 +1,237 @@
-use std::collections::{HashMap, HashSet};
 use std::fs::File;
 use std::io::Read;
+
 use nom::bytes::complete::tag;
-use nom::character::complete::{alpha1, newline};
+use nom::character::complete::{alpha1, anychar, newline};
 use nom::IResult;
 use nom::multi::separated_list1;
 use nom::sequence::separated_pair;
 
+/// Scrambled signal patterns and output value patterns of a single display
+type Note<'a> = (Vec<&'a str>, Vec<&'a str>);
+
+/// Bitmask of lit segments (or wires), indexed by position in the display's segment alphabet
+type Segments = u32;
+
 fn main() {
     let mut file = File::open("input/day08.txt").unwrap();
     let mut input = String::new();
     file.read_to_string(&mut input).unwrap();
     let input = input.as_str();
 
+    // Optionally select a built-in display or read a display definition from a file, defaults to
+    // the puzzle's 0-9 display
+    let display = match std::env::args().nth(1).as_deref() {
+        None | Some("decimal") => SegmentDisplay::seven_segment_decimal(),
+        Some("hex") => SegmentDisplay::seven_segment_hex(),
+        Some(path) => {
+            let mut file = File::open(path).unwrap();
+            let mut definition = String::new();
+            file.read_to_string(&mut definition).unwrap();
+            SegmentDisplay::parse(definition.trim_end()).unwrap()
+        }
+    };
+
     let (_, notes) = parse_input(input).unwrap();
 
     let mut easy_count = 0;
     let mut output_sum = 0;
 
     for (patterns, outputs) in &notes {
-        let mut digit_to_pattern: HashMap<usize, HashSet<char>> = HashMap::new();
-
-        // Find 1, 4, 7, 8
-        for &pattern in patterns {
-            let pattern: HashSet<char> = HashSet::from_iter(pattern.chars());
-            if let Some(digit) = match pattern.len() {
-                2 => Some(1),
-                3 => Some(7),
-                4 => Some(4),
-                7 => Some(8),
-                _ => None
-            } {
-                digit_to_pattern.insert(digit, pattern.clone());
+        let patterns: Vec<Segments> = patterns.iter()
+            .map(|pattern| display.segments_mask(pattern).unwrap())
+            .collect();
+        let wiring = display.deduce_wiring(&patterns).unwrap();
+
+        let mut output_symbols = vec![];
+        for &output in outputs {
+            if display.has_unique_length(output.len()) {
+                easy_count += 1;
             }
+
+            let output = display.segments_mask(output).unwrap();
+            output_symbols.push(display.decode(&wiring, output).unwrap());
         }
 
-        // Find 9, 0, 6
-        for &pattern in patterns {
-            let pattern: HashSet<char> = HashSet::from_iter(pattern.chars());
-            if pattern.len() == 6 {
-                if pattern.is_superset(&digit_to_pattern[&4]) {
-                    digit_to_pattern.insert(9, pattern.clone());
-                } else if pattern.is_superset(&digit_to_pattern[&7]) {
-                    digit_to_pattern.insert(0, pattern.clone());
-                } else {
-                    digit_to_pattern.insert(6, pattern.clone());
-                }
-            }
+        output_sum += display.value(&output_symbols).unwrap();
+    }
+
+    println!("Part 1: {}", easy_count);
+    println!("Part 2: {}", output_sum);
+}
+
+/// Describes which segments are lit for each symbol a display can show
+#[derive(Debug)]
+struct SegmentDisplay {
+    /// Names of the segments, wires use the same names
+    segments: Vec<char>,
+    glyphs: Vec<(char, Segments)>,
+}
+
+impl SegmentDisplay {
+    fn new(glyphs: &[(char, &str)]) -> SegmentDisplay {
+        let mut segments: Vec<char> = glyphs.iter()
+            .flat_map(|(_, lit)| lit.chars())
+            .collect();
+        segments.sort_unstable();
+        segments.dedup();
+        assert!(segments.len() <= Segments::BITS as usize, "too many segments");
+
+        let mut display = SegmentDisplay { segments, glyphs: vec![] };
+        display.glyphs = glyphs.iter()
+            .map(|&(symbol, lit)| (symbol, display.segments_mask(lit).unwrap()))
+            .collect();
+        display
+    }
+
+    /// The display from the puzzle, showing the digits 0-9
+    fn seven_segment_decimal() -> SegmentDisplay {
+        SegmentDisplay::new(&[
+            ('0', "abcefg"),
+            ('1', "cf"),
+            ('2', "acdeg"),
+            ('3', "acdfg"),
+            ('4', "bcdf"),
+            ('5', "abdfg"),
+            ('6', "abdefg"),
+            ('7', "acf"),
+            ('8', "abcdefg"),
+            ('9', "abcdfg"),
+        ])
+    }
+
+    /// Seven-segment display showing the hex digits 0-9 and A-F (with lowercase b and d)
+    fn seven_segment_hex() -> SegmentDisplay {
+        SegmentDisplay::new(&[
+            ('0', "abcefg"),
+            ('1', "cf"),
+            ('2', "acdeg"),
+            ('3', "acdfg"),
+            ('4', "bcdf"),
+            ('5', "abdfg"),
+            ('6', "abdefg"),
+            ('7', "acf"),
+            ('8', "abcdefg"),
+            ('9', "abcdfg"),
+            ('A', "abcdef"),
+            ('B', "bdefg"),
+            ('C', "abeg"),
+            ('D', "cdefg"),
+            ('E', "abdeg"),
+            ('F', "abde"),
+        ])
+    }
+
+    /// Parses a display definition, one `<symbol>: <segments>` line per glyph
+    fn parse(definition: &str) -> Option<SegmentDisplay> {
+        let (rest, glyphs) = parse_display_definition(definition).ok()?;
+
+        if !rest.is_empty() {
+            return None;
         }
+        Some(SegmentDisplay::new(&glyphs))
+    }
+
+    /// Converts a string of segment (or wire) names into a bitmask
+    fn segments_mask(&self, pattern: &str) -> Option<Segments> {
+        pattern.chars().try_fold(0, |mask, c| {
+            let index = self.segments.iter().position(|&s| s == c)?;
+            Some(mask | 1 << index)
+        })
+    }
+
+    /// Whether exactly one symbol lights this many segments
+    fn has_unique_length(&self, len: usize) -> bool {
+        self.glyphs.iter()
+            .filter(|(_, lit)| lit.count_ones() as usize == len)
+            .count() == 1
+    }
+
+    /// Finds which segment each wire is connected to, such that every pattern shows a different
+    /// symbol. Returns the segment for each wire, or `None` if the patterns are inconsistent with
+    /// this display or do not pin down a single wiring.
+    fn deduce_wiring(&self, patterns: &[Segments]) -> Option<Vec<Segments>> {
+        // Start with the patterns that can show the fewest symbols
+        let mut patterns = patterns.to_vec();
+        patterns.sort_by_key(|&pattern| {
+            self.glyphs.iter()
+                .filter(|(_, lit)| lit.count_ones() == pattern.count_ones())
+                .count()
+        });
 
-        // Find 3, 5, 2
-        for &pattern in patterns {
-            let pattern: HashSet<char> = HashSet::from_iter(pattern.chars());
-            if pattern.len() == 5 {
-                if pattern.is_superset(&digit_to_pattern[&1]) {
-                    digit_to_pattern.insert(3, pattern.clone());
-                } else if pattern.is_subset(&digit_to_pattern[&6]) {
-                    digit_to_pattern.insert(5, pattern.clone());
-                } else {
-                    digit_to_pattern.insert(2, pattern.clone());
-                }
+        let all_segments = Segments::MAX >> (Segments::BITS as usize - self.segments.len());
+        let candidates = vec![all_segments; self.segments.len()];
+        let mut used = vec![false; self.glyphs.len()];
+
+        self.assign_patterns(&patterns, candidates, &mut used)
+    }
+
+    /// Backtracking search assigning a symbol to each pattern, narrowing down the candidate
+    /// segments of each wire along the way
+    fn assign_patterns(
+        &self,
+        patterns: &[Segments],
+        candidates: Vec<Segments>,
+        used: &mut [bool],
+    ) -> Option<Vec<Segments>> {
+        let (&pattern, remaining) = match patterns.split_first() {
+            Some(split) => split,
+            None => {
+                // Every wire must end up on its own segment
+                let all_segments = candidates.iter().fold(0, |acc, c| acc | c);
+                let is_pinned = candidates.iter().all(|c| c.count_ones() == 1)
+                    && all_segments.count_ones() as usize == candidates.len();
+                return if is_pinned { Some(candidates) } else { None };
+            }
+        };
+
+        for (index, &(_, lit)) in self.glyphs.iter().enumerate() {
+            if used[index] || lit.count_ones() != pattern.count_ones() {
+                continue;
             }
-        }
 
-        let mut output_value = 0;
-        let mut pos = 1000;
-        for &output in outputs.iter() {
-            let output: HashSet<char> = HashSet::from_iter(output.chars());
-            match output.len() {
-                2 | 3 | 4 | 7 => {
-                    easy_count += 1;
-                }
-                _ => {}
+            // Wires in the pattern must drive a lit segment, the others an unlit one
+            let next_candidates: Vec<Segments> = candidates.iter()
+                .enumerate()
+                .map(|(wire, &c)| if pattern & 1 << wire != 0 { c & lit } else { c & !lit })
+                .collect();
+            if next_candidates.contains(&0) {
+                continue;
             }
 
-            let digit = digit_to_pattern.iter().find_map(|(digit, pattern)| {
-                if pattern.clone() == output {
-                    Some(digit)
-                } else {
-                    None
-                }
-            }).unwrap();
-            output_value += pos * digit;
-            pos /= 10;
+            used[index] = true;
+            if let Some(result) = self.assign_patterns(remaining, next_candidates, used) {
+                return Some(result);
+            }
+            used[index] = false;
         }
 
-        output_sum += output_value;
+        None
     }
 
-    println!("Part 1: {}", easy_count);
-    println!("Part 2: {}", output_sum);
+    /// Gets the symbol shown by the given wires
+    fn decode(&self, wiring: &[Segments], wires: Segments) -> Option<char> {
+        let lit = wiring.iter()
+            .enumerate()
+            .filter(|&(wire, _)| wires & 1 << wire != 0)
+            .fold(0, |acc, (_, &segment)| acc | segment);
+
+        self.glyphs.iter()
+            .find(|&&(_, glyph)| glyph == lit)
+            .map(|&(symbol, _)| symbol)
+    }
+
+    /// Reads symbols as a number, using each symbol's position in the display definition as its
+    /// digit value and the number of symbols as the base
+    fn value(&self, symbols: &[char]) -> Option<usize> {
+        symbols.iter().try_fold(0, |acc, &symbol| {
+            let digit = self.glyphs.iter().position(|&(s, _)| s == symbol)?;
+            Some(acc * self.glyphs.len() + digit)
+        })
+    }
 }
 
-fn parse_input(input: &str) -> IResult<&str, Vec<(Vec<&str>, Vec<&str>)>> {
+fn parse_input(input: &str) -> IResult<&str, Vec<Note<'_>>> {
     separated_list1(
         newline,
         separated_pair(
@@ -102,3 +241,10 @@ fn parse_input(input: &str) -> IResult<&str, Vec<(Vec<&str>, Vec<&str>)>> {
         ),
     )(input)
 }
+
+fn parse_display_definition(input: &str) -> IResult<&str, Vec<(char, &str)>> {
+    separated_list1(
+        newline,
+        separated_pair(anychar, tag(": "), alpha1),
+    )(input)
+}
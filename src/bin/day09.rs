@@ -1,6 +1,5 @@
-use std::collections::{HashSet, VecDeque};
 use std::fs::File;
-use std::io::Read;
+use std::io::{Read, Write};
 
 use nom::character::complete::{digit1, newline};
 use nom::combinator::map;
@@ -15,75 +14,268 @@ fn main() {
 
     let (_, grid) = parse_input(input).unwrap();
 
+    // Usage: day09 [--eight] [--wall <height>] [--list] [--export <path.ppm>]
+    let mut config = BasinConfig::default();
+    let mut list_basins = false;
+    let mut export_path = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--eight" => config.connectivity = Connectivity::Eight,
+            "--wall" => config.wall_height = args.next().unwrap().parse().unwrap(),
+            "--list" => list_basins = true,
+            "--export" => export_path = args.next(),
+            _ => panic!("unknown argument: {}", arg),
+        }
+    }
+
     let width = grid.len();
     let height = grid[0].len();
 
-    let valid_neighbors = |x: usize, y: usize| -> Vec<(usize, usize)> {
-        let mut neighbors = vec![];
-        if x > 0 {
-            neighbors.push((x - 1, y));
-        }
-        if x + 1 < width {
-            neighbors.push((x + 1, y));
+    let mut sum_risk_level = 0;
+
+    for x in 0..width {
+        for y in 0..height {
+            let is_low_point = config.connectivity.neighbors(x, y, width, height)
+                .into_iter()
+                .all(|(nx, ny)| grid[x][y] < grid[nx][ny]);
+
+            if is_low_point {
+                sum_risk_level += grid[x][y] + 1;
+            }
         }
-        if y > 0 {
-            neighbors.push((x, y - 1));
+    }
+
+    let basin_map = label_basins(&grid, &config);
+
+    let mut basin_sizes: Vec<usize> = basin_map.basins.iter()
+        .map(|basin| basin.size)
+        .collect();
+    basin_sizes.sort_unstable();
+    let part2: usize = basin_sizes.iter().rev().take(3).product();
+
+    println!("Part 1: {}", sum_risk_level);
+    println!("Part 2: {}", part2);
+
+    if list_basins {
+        for (label, basin) in basin_map.basins.iter().enumerate() {
+            println!(
+                "Basin {}: low point {:?}, size {}, depth profile {:?}",
+                label, basin.low_point, basin.size, basin.depth_profile,
+            );
         }
-        if y + 1 < height {
-            neighbors.push((x, y + 1));
+    }
+
+    if let Some(path) = export_path {
+        let mut file = File::create(path).unwrap();
+        file.write_all(basin_map.to_ppm(&grid, &config).as_bytes()).unwrap();
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+enum Connectivity {
+    Four,
+    Eight,
+}
+
+impl Connectivity {
+    fn neighbors(self, x: usize, y: usize, width: usize, height: usize) -> Vec<(usize, usize)> {
+        let mut neighbors = vec![];
+        for dx in -1..=1_isize {
+            for dy in -1..=1_isize {
+                let is_diagonal = dx != 0 && dy != 0;
+                if (dx == 0 && dy == 0) || (is_diagonal && matches!(self, Connectivity::Four)) {
+                    continue;
+                }
+
+                let nx = x as isize + dx;
+                let ny = y as isize + dy;
+                if 0 <= nx && nx < width as isize && 0 <= ny && ny < height as isize {
+                    neighbors.push((nx as usize, ny as usize));
+                }
+            }
         }
         neighbors
+    }
+}
+
+#[derive(Debug)]
+struct BasinConfig {
+    connectivity: Connectivity,
+    /// Locations at least this high are not part of any basin
+    wall_height: u32,
+}
+
+impl Default for BasinConfig {
+    fn default() -> Self {
+        BasinConfig {
+            connectivity: Connectivity::Four,
+            wall_height: 9,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Basin {
+    low_point: (usize, usize),
+    size: usize,
+    /// Number of locations in the basin at each height
+    depth_profile: Vec<usize>,
+}
+
+#[derive(Debug)]
+struct BasinMap {
+    /// Basin index for each location, `None` for walls
+    labels: Vec<Vec<Option<usize>>>,
+    basins: Vec<Basin>,
+}
+
+impl BasinMap {
+    /// Renders the basins as a plain PPM image, giving each basin its own colour (darker towards
+    /// the low point) and walls in black
+    fn to_ppm(&self, grid: &[Vec<u32>], config: &BasinConfig) -> String {
+        let width = self.labels.len();
+        let height = self.labels[0].len();
+
+        let mut ppm = format!("P3\n{} {}\n255\n", height, width);
+        for (x, row) in self.labels.iter().enumerate() {
+            let pixels: Vec<String> = row.iter()
+                .enumerate()
+                .map(|(y, label)| {
+                    let (r, g, b) = match label {
+                        Some(label) => {
+                            let (r, g, b) = basin_colour(*label);
+                            let shade = (grid[x][y] + 1) as f32 / config.wall_height as f32;
+                            let shade = |c: u8| (c as f32 * shade) as u8;
+                            (shade(r), shade(g), shade(b))
+                        }
+                        None => (0, 0, 0),
+                    };
+                    format!("{} {} {}", r, g, b)
+                })
+                .collect();
+            ppm.push_str(&pixels.join(" "));
+            ppm.push('\n');
+        }
+        ppm
+    }
+}
+
+/// Picks a bright colour for the basin with the given label, spreading consecutive labels over
+/// the hue circle
+fn basin_colour(label: usize) -> (u8, u8, u8) {
+    // Golden angle steps keep neighbouring labels visually distinct
+    let hue = (label as f32 * 137.508) % 360.0;
+    let x = 1.0 - ((hue / 60.0) % 2.0 - 1.0).abs();
+    let (r, g, b) = match (hue / 60.0) as u32 {
+        0 => (1.0, x, 0.0),
+        1 => (x, 1.0, 0.0),
+        2 => (0.0, 1.0, x),
+        3 => (0.0, x, 1.0),
+        4 => (x, 0.0, 1.0),
+        _ => (1.0, 0.0, x),
     };
+    ((r * 255.0) as u8, (g * 255.0) as u8, (b * 255.0) as u8)
+}
 
-    let mut sum_risk_level = 0;
-    let mut basin_sizes: Vec<usize> = vec![];
+struct UnionFind {
+    parent: Vec<usize>,
+    rank: Vec<u8>,
+}
+
+impl UnionFind {
+    fn new(size: usize) -> Self {
+        UnionFind {
+            parent: (0..size).collect(),
+            rank: vec![0; size],
+        }
+    }
+
+    fn find(&mut self, mut i: usize) -> usize {
+        while self.parent[i] != i {
+            // Path halving
+            self.parent[i] = self.parent[self.parent[i]];
+            i = self.parent[i];
+        }
+        i
+    }
+
+    fn union(&mut self, i: usize, j: usize) {
+        let i = self.find(i);
+        let j = self.find(j);
+        if i == j {
+            return;
+        }
+
+        if self.rank[i] < self.rank[j] {
+            self.parent[i] = j;
+        } else {
+            self.parent[j] = i;
+            if self.rank[i] == self.rank[j] {
+                self.rank[i] += 1;
+            }
+        }
+    }
+}
+
+/// Labels all basins in a single pass over the grid, joining each location with its already
+/// visited neighbors
+fn label_basins(grid: &[Vec<u32>], config: &BasinConfig) -> BasinMap {
+    let width = grid.len();
+    let height = grid[0].len();
+    let index = |x: usize, y: usize| x * height + y;
+    let is_wall = |x: usize, y: usize| grid[x][y] >= config.wall_height;
+
+    let mut union_find = UnionFind::new(width * height);
 
     for x in 0..width {
         for y in 0..height {
-            let mut is_low_point = true;
+            if is_wall(x, y) {
+                continue;
+            }
 
-            for (nx, ny) in valid_neighbors(x, y) {
-                if grid[x][y] >= grid[nx][ny] {
-                    is_low_point = false;
-                    break;
+            for (nx, ny) in config.connectivity.neighbors(x, y, width, height) {
+                let is_visited = (nx, ny) < (x, y);
+                if is_visited && !is_wall(nx, ny) {
+                    union_find.union(index(x, y), index(nx, ny));
                 }
             }
+        }
+    }
 
-            if is_low_point {
-                sum_risk_level += grid[x][y] + 1;
+    // Number the basins in order of first appearance
+    let mut root_to_label = vec![None; width * height];
+    let mut labels = vec![vec![None; height]; width];
+    let mut basins: Vec<Basin> = vec![];
 
-                // Find basin size
-                let mut basin_size = 0;
-                let mut q: VecDeque<(usize, usize)> = VecDeque::new();
-                let mut closed_set: HashSet<(usize, usize)> = HashSet::new();
-                q.push_back((x, y));
-
-                while let Some((x, y)) = q.pop_front() {
-                    if grid[x][y] == 9 {
-                        continue;
-                    }
-                    if closed_set.contains(&(x, y)) {
-                        continue;
-                    }
-
-                    for (nx, ny) in valid_neighbors(x, y) {
-                        q.push_back((nx, ny));
-                    }
-
-                    basin_size += 1;
-                    closed_set.insert((x, y));
-                }
+    for x in 0..width {
+        for y in 0..height {
+            if is_wall(x, y) {
+                continue;
+            }
+
+            let root = union_find.find(index(x, y));
+            let label = *root_to_label[root].get_or_insert_with(|| {
+                basins.push(Basin {
+                    low_point: (x, y),
+                    size: 0,
+                    depth_profile: vec![0; config.wall_height as usize],
+                });
+                basins.len() - 1
+            });
+            labels[x][y] = Some(label);
 
-                basin_sizes.push(basin_size);
+            let basin = &mut basins[label];
+            basin.size += 1;
+            basin.depth_profile[grid[x][y] as usize] += 1;
+            let (lx, ly) = basin.low_point;
+            if grid[x][y] < grid[lx][ly] {
+                basin.low_point = (x, y);
             }
         }
     }
 
-    basin_sizes.sort();
-    let part2 = basin_sizes[basin_sizes.len() - 1] * basin_sizes[basin_sizes.len() - 2] * basin_sizes[basin_sizes.len() - 3];
-
-    println!("Part 1: {}", sum_risk_level);
-    println!("Part 2: {}", part2);
+    BasinMap { labels, basins }
 }
 
 fn parse_input(input: &str) -> IResult<&str, Vec<Vec<u32>>> {
@@ -1,76 +1,282 @@
-use std::collections::VecDeque;
+use std::fmt::{Display, Formatter};
 use std::fs::File;
 use std::io::{BufRead, BufReader};
 
-
 fn main() {
-    let file = File::open("input/day10.txt").unwrap();
-    let buf_reader = BufReader::new(file);
-    let lines = buf_reader.lines();
+    // Usage: day10 [--report] [<line>...]
+    // Checks the given lines instead of the input file if any are passed.
+    let mut report = false;
+    let mut lines = vec![];
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "--report" => report = true,
+            _ => lines.push(arg),
+        }
+    }
+
+    let checker = SyntaxChecker::default();
+
+    let results: Vec<CheckResult> = if lines.is_empty() {
+        let file = File::open("input/day10.txt").unwrap();
+        checker.check_lines(BufReader::new(file))
+            .map(|result| result.unwrap())
+            .collect()
+    } else {
+        lines.iter()
+            .map(|line| checker.check_line(line))
+            .collect()
+    };
 
     let mut corrupted_score = 0;
     let mut incomplete_scores = vec![];
 
-    for line in lines {
-        let line = line.unwrap();
-        match parse_line(line.as_str()) {
-            ParseError::Corrupted(c) => {
-                corrupted_score += match c {
-                    ')' => 3,
-                    ']' => 57,
-                    '}' => 1197,
-                    '>' => 25137,
-                    _ => panic!(),
-                };
+    for (line, result) in results.iter().enumerate() {
+        if report {
+            println!("Line {}: {}", line + 1, result);
+        }
+
+        match result {
+            CheckResult::Valid | CheckResult::NonAscii { .. } => {}
+            CheckResult::Corrupted { found, .. } => {
+                corrupted_score += checker.corrupted_score(*found);
             }
-            ParseError::Incomplete(chars) => {
-                let mut score = 0;
-                for c in chars {
-                    score *= 5;
-                    score += match c {
-                        ')' => 1,
-                        ']' => 2,
-                        '}' => 3,
-                        '>' => 4,
-                        _ => panic!(),
-                    };
-                }
-                incomplete_scores.push(score);
+            CheckResult::Incomplete { completion, .. } => {
+                incomplete_scores.push(checker.completion_score(completion));
             }
         }
     }
 
-    incomplete_scores.sort();
+    incomplete_scores.sort_unstable();
 
     println!("Part 1: {}", corrupted_score);
-    println!("Part 2: {}", incomplete_scores[incomplete_scores.len() / 2]);
+    if !incomplete_scores.is_empty() {
+        println!("Part 2: {}", incomplete_scores[incomplete_scores.len() / 2]);
+    }
+}
+
+/// A pair of matching delimiters and their scores
+#[derive(Debug, Copy, Clone)]
+struct Delimiter {
+    open: char,
+    close: char,
+    /// Score for finding this closing character where another one was expected
+    corrupted_score: usize,
+    /// Score for having to append this closing character to complete a line
+    completion_score: usize,
 }
 
 #[derive(Debug)]
-enum ParseError {
-    Corrupted(char),
-    Incomplete(Vec<char>),
+struct SyntaxChecker {
+    delimiters: Vec<Delimiter>,
+    /// The completion score is multiplied by this before adding each character's score
+    completion_multiplier: usize,
+}
+
+impl Default for SyntaxChecker {
+    /// The checker from the puzzle, with the four bracket pairs and their scores
+    fn default() -> Self {
+        SyntaxChecker::new(
+            vec![
+                Delimiter { open: '(', close: ')', corrupted_score: 3, completion_score: 1 },
+                Delimiter { open: '[', close: ']', corrupted_score: 57, completion_score: 2 },
+                Delimiter { open: '{', close: '}', corrupted_score: 1197, completion_score: 3 },
+                Delimiter { open: '<', close: '>', corrupted_score: 25137, completion_score: 4 },
+            ],
+            5,
+        )
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+enum CheckResult {
+    /// Every chunk is closed
+    Valid,
+    /// A closing character did not match the innermost open chunk
+    Corrupted {
+        /// Byte offset of the illegal character
+        offset: usize,
+        found: char,
+        expected: Option<char>,
+        /// Byte offset of the opening character of the innermost open chunk, if any
+        opener_offset: Option<usize>,
+    },
+    /// The line ended with open chunks
+    Incomplete {
+        /// Closing characters needed to complete the line
+        completion: Vec<char>,
+        /// Byte offsets of the unmatched opening characters, innermost first
+        opener_offsets: Vec<usize>,
+    },
+    /// The line contains a character that is not ASCII, so it cannot be a delimiter
+    NonAscii {
+        /// Byte offset of the first byte of the character
+        offset: usize,
+    },
 }
 
-fn parse_line(input: &str) -> ParseError {
-    let mut input = VecDeque::from_iter(input.chars());
-    let mut stack = vec![];
-
-    while let Some(first_char) = input.pop_front() {
-        match first_char {
-            '(' => stack.push(')'),
-            '[' => stack.push(']'),
-            '{' => stack.push('}'),
-            '<' => stack.push('>'),
-            _ => {
-                let c = stack.pop();
-                if c.is_none() || c.unwrap() != first_char {
-                    return ParseError::Corrupted(first_char)
+impl Display for CheckResult {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CheckResult::Valid => write!(f, "valid"),
+            CheckResult::Corrupted { offset, found, expected, opener_offset } => {
+                write!(f, "corrupted at {}, found {:?}", offset, found)?;
+                match (expected, opener_offset) {
+                    (Some(expected), Some(opener_offset)) => {
+                        write!(f, ", expected {:?} to close {}", expected, opener_offset)
+                    }
+                    _ => write!(f, ", no chunk is open"),
                 }
             }
+            CheckResult::Incomplete { completion, opener_offsets } => {
+                write!(
+                    f,
+                    "incomplete, complete with {:?} to close {:?}",
+                    completion.iter().collect::<String>(),
+                    opener_offsets,
+                )
+            }
+            CheckResult::NonAscii { offset } => write!(f, "non-ASCII character at {}", offset),
         }
     }
+}
+
+/// Incremental state of the check of a single line
+#[derive(Debug, Default)]
+struct LineState {
+    /// Offset of the next byte
+    offset: usize,
+    /// Open chunks, with the offset of their opening character
+    stack: Vec<(usize, Delimiter)>,
+    /// The result of the line once it is known to be corrupted or not ASCII
+    corrupted: Option<CheckResult>,
+}
+
+impl SyntaxChecker {
+    /// Creates a checker for the given delimiter pairs, which must be ASCII characters
+    fn new(delimiters: Vec<Delimiter>, completion_multiplier: usize) -> Self {
+        assert!(
+            delimiters.iter().all(|d| d.open.is_ascii() && d.close.is_ascii()),
+            "delimiters must be ASCII characters",
+        );
+        SyntaxChecker { delimiters, completion_multiplier }
+    }
 
-    stack.reverse();
-    ParseError::Incomplete(stack)
+    fn check_line(&self, line: &str) -> CheckResult {
+        let mut state = LineState::default();
+        for &b in line.as_bytes() {
+            self.feed(&mut state, b);
+        }
+        self.finish(state)
+    }
+
+    /// Checks each line read from `reader`, without holding on to the line contents
+    fn check_lines<R: BufRead>(&self, reader: R) -> CheckLines<'_, R> {
+        CheckLines { checker: self, reader }
+    }
+
+    fn feed(&self, state: &mut LineState, b: u8) {
+        let offset = state.offset;
+        state.offset += 1;
+
+        if state.corrupted.is_some() {
+            return;
+        }
+
+        // Bytes of multi-byte UTF-8 characters are not characters on their own
+        if !b.is_ascii() {
+            state.corrupted = Some(CheckResult::NonAscii { offset });
+            return;
+        }
+
+        let c = b as char;
+        if let Some(&delimiter) = self.delimiters.iter().find(|d| d.open == c) {
+            state.stack.push((offset, delimiter));
+            return;
+        }
+
+        match state.stack.pop() {
+            Some((_, delimiter)) if delimiter.close == c => {}
+            innermost => {
+                state.corrupted = Some(CheckResult::Corrupted {
+                    offset,
+                    found: c,
+                    expected: innermost.map(|(_, d)| d.close),
+                    opener_offset: innermost.map(|(offset, _)| offset),
+                });
+            }
+        }
+    }
+
+    fn finish(&self, state: LineState) -> CheckResult {
+        if let Some(corrupted) = state.corrupted {
+            return corrupted;
+        }
+        if state.stack.is_empty() {
+            return CheckResult::Valid;
+        }
+
+        let (opener_offsets, completion) = state.stack.iter()
+            .rev()
+            .map(|&(offset, delimiter)| (offset, delimiter.close))
+            .unzip();
+        CheckResult::Incomplete { completion, opener_offsets }
+    }
+
+    fn corrupted_score(&self, found: char) -> usize {
+        self.delimiters.iter()
+            .find(|d| d.close == found)
+            .map_or(0, |d| d.corrupted_score)
+    }
+
+    fn completion_score(&self, completion: &[char]) -> usize {
+        completion.iter().fold(0, |score, &c| {
+            let char_score = self.delimiters.iter()
+                .find(|d| d.close == c)
+                .map_or(0, |d| d.completion_score);
+            score * self.completion_multiplier + char_score
+        })
+    }
+}
+
+/// Iterator over the check results of each line in a reader
+struct CheckLines<'a, R> {
+    checker: &'a SyntaxChecker,
+    reader: R,
+}
+
+impl<'a, R: BufRead> Iterator for CheckLines<'a, R> {
+    type Item = std::io::Result<CheckResult>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut state = LineState::default();
+        let mut is_empty = true;
+
+        loop {
+            let buf = match self.reader.fill_buf() {
+                Ok(buf) => buf,
+                Err(e) => return Some(Err(e)),
+            };
+            if buf.is_empty() {
+                // End of input, the last line may not end with a newline
+                return if is_empty { None } else { Some(Ok(self.checker.finish(state))) };
+            }
+            is_empty = false;
+
+            let line_end = buf.iter().position(|&b| b == b'\n');
+            let line_bytes = &buf[..line_end.unwrap_or(buf.len())];
+            for &b in line_bytes {
+                // Ignore the carriage return of CRLF line endings
+                if b != b'\r' {
+                    self.checker.feed(&mut state, b);
+                }
+            }
+
+            let consumed = line_bytes.len() + line_end.map_or(0, |_| 1);
+            self.reader.consume(consumed);
+
+            if line_end.is_some() {
+                return Some(Ok(self.checker.finish(state)));
+            }
+        }
+    }
 }
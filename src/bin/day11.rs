@@ -1,4 +1,4 @@
-use std::collections::{HashSet, VecDeque};
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::Read;
 
@@ -13,70 +13,143 @@ fn main() {
     file.read_to_string(&mut input).unwrap();
     let input = input.as_str();
 
-    let (_, mut grid) = parse_input(input).unwrap();
-    let width = grid.len();
-    let height = grid[0].len();
-
-    let valid_neighbors = |x: usize, y: usize| {
-        let mut neighbors = vec![];
-        for ix in 0..3 {
-            if (ix == 0 && x == 0) || (ix == 2 && x == width - 1) {
-                continue;
-            }
-            for iy in 0..3 {
-                if (iy == 0 && y == 0) || (iy == 2 && y == height - 1) || (iy == 1 && ix == 1) {
-                    continue;
-                }
-                neighbors.push((x + ix - 1, y + iy - 1));
-            }
+    // Usage: day11 [--threshold <energy>] [--trace <steps>]
+    let mut threshold = 9;
+    let mut trace_steps = 0;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--threshold" => threshold = args.next().unwrap().parse().unwrap(),
+            "--trace" => trace_steps = args.next().unwrap().parse().unwrap(),
+            _ => panic!("unknown argument: {}", arg),
         }
-        neighbors
-    };
+    }
 
-    let mut step = 0;
-    let mut flash_count = 0;
+    let (_, grid) = parse_input(input).unwrap();
+    let octopuses = Octopuses::new(grid, threshold);
+    let size = octopuses.width * octopuses.height;
 
-    loop {
-        let mut next_grid = grid.clone();
-        let mut flashed = HashSet::new();
+    for (step, flashed) in (1..=trace_steps).zip(octopuses.clone()) {
+        println!("Step {}: {} flashes at {:?}", step, flashed.len(), flashed);
+    }
 
-        for x in 0..width {
-            for y in 0..height {
-                let mut q = VecDeque::new();
-                q.push_back((x, y));
+    let flash_count: usize = octopuses.clone()
+        .take(100)
+        .map(|flashed| flashed.len())
+        .sum();
+
+    // Once the grid is periodic without having synchronized, it never will
+    let cycle = octopuses.find_cycle();
+    let first_synchronized = (1..=cycle.start + cycle.period)
+        .zip(octopuses)
+        .find(|(_, flashed)| flashed.len() == size)
+        .map(|(step, _)| step);
+
+    println!("Part 1: {}", flash_count);
+    match first_synchronized {
+        Some(step) => println!("Part 2: {}", step),
+        None => println!("Part 2: never synchronized"),
+    }
+    println!("Periodic from step {} with period {}", cycle.start, cycle.period);
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Cycle {
+    /// First step of the periodic part
+    start: usize,
+    period: usize,
+}
 
-                while let Some((x, y)) = q.pop_front() {
-                    next_grid[x][y] += 1;
+#[derive(Debug, Clone)]
+struct Octopuses {
+    width: usize,
+    height: usize,
+    /// Energy levels in row-major order
+    energy: Vec<u32>,
+    /// An octopus flashes when its energy level rises above this
+    threshold: u32,
+}
 
-                    if next_grid[x][y] > 9 && !flashed.contains(&(x, y)) {
-                        flashed.insert((x, y));
+impl Octopuses {
+    fn new(grid: Vec<Vec<u32>>, threshold: u32) -> Self {
+        let width = grid.len();
+        let height = grid[0].len();
+        assert!(grid.iter().all(|row| row.len() == height), "grid must be rectangular");
+
+        Octopuses {
+            width,
+            height,
+            energy: grid.into_iter().flatten().collect(),
+            threshold,
+        }
+    }
 
-                        for (nx, ny) in valid_neighbors(x, y) {
-                            q.push_back((nx, ny));
-                        }
-                    }
+    fn neighbors(&self, x: usize, y: usize) -> impl Iterator<Item = (usize, usize)> {
+        let (width, height) = (self.width, self.height);
+        (x.saturating_sub(1)..=usize::min(x + 1, width - 1))
+            .flat_map(move |nx| {
+                (y.saturating_sub(1)..=usize::min(y + 1, height - 1)).map(move |ny| (nx, ny))
+            })
+            .filter(move |&n| n != (x, y))
+    }
+
+    /// Advances the simulation by one step, returning the octopuses that flashed
+    fn step(&mut self) -> Vec<(usize, usize)> {
+        let mut flashing = vec![];
+        for (i, energy) in self.energy.iter_mut().enumerate() {
+            *energy += 1;
+            if *energy > self.threshold {
+                flashing.push((i / self.height, i % self.height));
+            }
+        }
+
+        // Each octopus is pushed exactly once, when its energy first exceeds the threshold. Energy
+        // levels are at most the threshold after each step, except possibly in the initial grid.
+        let mut flashed = vec![];
+        while let Some((x, y)) = flashing.pop() {
+            flashed.push((x, y));
+
+            for (nx, ny) in self.neighbors(x, y) {
+                let energy = &mut self.energy[nx * self.height + ny];
+                *energy += 1;
+                if *energy == self.threshold + 1 {
+                    flashing.push((nx, ny));
                 }
             }
         }
 
         for &(x, y) in &flashed {
-            next_grid[x][y] = 0;
-            flash_count += 1;
+            self.energy[x * self.height + y] = 0;
         }
 
-        grid = next_grid;
-        step += 1;
-
-        if step == 100 {
-            println!("Part 1: {}", flash_count);
-        }
+        flashed.sort_unstable();
+        flashed
+    }
 
-        if flashed.len() == 100 {
-            println!("Part 2: {}", step);
-            break;
+    /// Simulates until the energy levels repeat. The simulation is deterministic, so from then on
+    /// the steps repeat with a fixed period.
+    fn find_cycle(&self) -> Cycle {
+        let mut octopuses = self.clone();
+        let mut seen = HashMap::new();
+        seen.insert(octopuses.energy.clone(), 0);
+
+        for step in 1.. {
+            octopuses.step();
+            if let Some(start) = seen.insert(octopuses.energy.clone(), step) {
+                return Cycle { start, period: step - start };
+            }
         }
+        unreachable!()
     }
+}
+
+/// Yields the octopuses that flashed in each step
+impl Iterator for Octopuses {
+    type Item = Vec<(usize, usize)>;
 
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(self.step())
+    }
 }
 
 fn parse_input(input: &str) -> IResult<&str, Vec<Vec<u32>>> {
@@ -1,4 +1,4 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::Read;
 
@@ -14,39 +14,163 @@ fn main() {
     file.read_to_string(&mut input).unwrap();
     let input = input.as_str();
 
-    let mut adjacency_map: HashMap<&str, HashSet<&str>> = HashMap::new();
-    for (c1, c2) in parse_input(input).unwrap().1 {
-        adjacency_map.entry(c1).or_insert(HashSet::new()).insert(c2);
-        adjacency_map.entry(c2).or_insert(HashSet::new()).insert(c1);
+    // Usage: day12 [--revisits <k>] [--paths <k>]
+    // <k> is a total number of extra visits shared by all small caves, so with k = 2 one small
+    // cave can be visited three times, or two small caves twice each
+    let mut count_revisits = None;
+    let mut paths_revisits = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--revisits" => count_revisits = Some(args.next().unwrap().parse().unwrap()),
+            "--paths" => paths_revisits = Some(args.next().unwrap().parse().unwrap()),
+            _ => panic!("unknown argument: {}", arg),
+        }
+    }
+
+    let cave_system = CaveSystem::new(&parse_input(input).unwrap().1);
+
+    println!("Part 1: {}", cave_system.count_paths(0));
+    println!("Part 2: {}", cave_system.count_paths(1));
+
+    if let Some(revisits) = count_revisits {
+        println!("Paths with {} revisits: {}", revisits, cave_system.count_paths(revisits));
     }
 
-    println!("Part 1: {}", count_paths(&adjacency_map, "start", HashSet::new(), true));
-    println!("Part 2: {}", count_paths(&adjacency_map, "start", HashSet::new(), false));
+    if let Some(revisits) = paths_revisits {
+        for path in cave_system.paths(revisits) {
+            println!("{}", path.join(","));
+        }
+    }
+}
+
+/// Caves interned by index, so sets of visited small caves fit in a bitmask
+#[derive(Debug)]
+struct CaveSystem<'a> {
+    names: Vec<&'a str>,
+    adjacency: Vec<Vec<usize>>,
+    start: usize,
+    end: usize,
 }
 
-fn count_paths<'a>(
-    adjacency_map: &HashMap<&'a str, HashSet<&'a str>>,
-    cave: &'a str,
-    mut closed: HashSet<&'a str>,
-    is_joker_used: bool,
-) -> usize {
-    if cave == "end" {
-        return 1;
+type Visited = u64;
+
+impl<'a> CaveSystem<'a> {
+    fn new(connections: &[(&'a str, &'a str)]) -> Self {
+        let mut names: Vec<&str> = vec![];
+        let mut adjacency: Vec<Vec<usize>> = vec![];
+
+        let mut intern = |name: &'a str| -> usize {
+            names.iter().position(|&n| n == name).unwrap_or_else(|| {
+                names.push(name);
+                adjacency.push(vec![]);
+                names.len() - 1
+            })
+        };
+
+        let connections: Vec<(usize, usize)> = connections.iter()
+            .map(|&(c1, c2)| (intern(c1), intern(c2)))
+            .collect();
+        for (c1, c2) in connections {
+            adjacency[c1].push(c2);
+            adjacency[c2].push(c1);
+        }
+
+        assert!(names.len() <= Visited::BITS as usize, "too many caves");
+
+        let start = names.iter().position(|&n| n == "start").unwrap();
+        let end = names.iter().position(|&n| n == "end").unwrap();
+        CaveSystem { names, adjacency, start, end }
+    }
+
+    fn is_big(&self, cave: usize) -> bool {
+        is_big(self.names[cave])
     }
 
-    if !is_big(cave) {
-        closed.insert(cave);
+    /// Counts the paths from start to end that visit small caves at most once, except for
+    /// `revisits` extra visits shared between all small caves other than start and end. The
+    /// budget is not per cave: any single cave can use all of it, but the extra visits of all
+    /// caves add up to at most `revisits`.
+    fn count_paths(&self, revisits: usize) -> usize {
+        let mut cache = HashMap::new();
+        self.count_paths_from(&mut cache, self.start, 0, revisits)
+    }
+
+    fn count_paths_from(
+        &self,
+        cache: &mut HashMap<(usize, Visited, usize), usize>,
+        cave: usize,
+        mut visited: Visited,
+        revisits: usize,
+    ) -> usize {
+        if cave == self.end {
+            return 1;
+        }
+
+        let key = (cave, visited, revisits);
+        if let Some(&count) = cache.get(&key) {
+            return count;
+        }
+
+        if !self.is_big(cave) {
+            visited |= 1 << cave;
+        }
+
+        let mut sum = 0;
+        for &adj_cave in &self.adjacency[cave] {
+            if let Some(revisits) = self.remaining_revisits(visited, adj_cave, revisits) {
+                sum += self.count_paths_from(cache, adj_cave, visited, revisits);
+            }
+        }
+
+        cache.insert(key, sum);
+        sum
+    }
+
+    /// Enumerates the paths counted by `count_paths`
+    fn paths(&self, revisits: usize) -> Vec<Vec<&'a str>> {
+        let mut paths = vec![];
+        self.extend_paths_from(&mut paths, &mut vec![], self.start, 0, revisits);
+        paths
+    }
+
+    fn extend_paths_from(
+        &self,
+        paths: &mut Vec<Vec<&'a str>>,
+        path: &mut Vec<&'a str>,
+        cave: usize,
+        mut visited: Visited,
+        revisits: usize,
+    ) {
+        path.push(self.names[cave]);
+
+        if cave == self.end {
+            paths.push(path.clone());
+        } else {
+            if !self.is_big(cave) {
+                visited |= 1 << cave;
+            }
+
+            for &adj_cave in &self.adjacency[cave] {
+                if let Some(revisits) = self.remaining_revisits(visited, adj_cave, revisits) {
+                    self.extend_paths_from(paths, path, adj_cave, visited, revisits);
+                }
+            }
+        }
+
+        path.pop();
     }
 
-    let mut sum = 0;
-    for &adj_cave in &adjacency_map[cave] {
-        if !closed.contains(adj_cave) {
-            sum += count_paths(&adjacency_map, adj_cave, closed.clone(), is_joker_used);
-        } else if !is_joker_used && adj_cave != "start" && adj_cave != "end" {
-            sum += count_paths(&adjacency_map, adj_cave, closed.clone(), true);
+    /// Gets the revisits left after entering `cave`, or `None` if it cannot be entered
+    fn remaining_revisits(&self, visited: Visited, cave: usize, revisits: usize) -> Option<usize> {
+        if visited & 1 << cave == 0 {
+            Some(revisits)
+        } else if revisits > 0 && cave != self.start && cave != self.end {
+            Some(revisits - 1)
+        } else {
+            None
         }
     }
-    sum
 }
 
 fn is_big(cave: &str) -> bool {
@@ -1,4 +1,5 @@
 use std::collections::HashSet;
+use std::fmt::{Display, Formatter};
 use std::fs::File;
 use std::io::Read;
 
@@ -16,36 +17,116 @@ fn main() {
     file.read_to_string(&mut input).unwrap();
     let input = input.as_str();
 
+    // Usage: day13 [--render]
+    let render = std::env::args().skip(1).any(|arg| arg == "--render");
+
     let (_, (points, folds)) = parse_input(input).unwrap();
 
-    let mut grid = HashSet::new();
-    for (x, y) in points {
-        grid.insert((x, y));
-    }
+    let paper = Paper::new(points);
 
-    let mut is_first = true;
-
-    for fold in folds {
-        let mut next_grid = HashSet::new();
-        for (x, y) in grid {
-            let (nx, ny) = match fold {
-                FoldInstruction::Up(fy) if y > fy => (x, 2 * fy - y),
-                FoldInstruction::Left(fx) if x > fx => (2 * fx - x, y),
-                _ => (x, y),
-            };
-            next_grid.insert((nx, ny));
+    let folded = match paper.fold_all(&folds) {
+        Ok(folded) => folded,
+        Err((index, e)) => {
+            println!("Fold {} failed: {}", index + 1, e);
+            return;
         }
+    };
+
+    println!("Part 1: {}", folded[0].dots.len());
+
+    let grid = &folded.last().unwrap().dots;
+    match recognize_letters(grid) {
+        Ok(letters) => println!("Part 2: {}", letters),
+        Err(e) => println!("Part 2: {}", e),
+    }
+
+    if render {
+        print_grid(grid);
+    }
+}
 
-        if is_first {
-            is_first = false;
-            println!("Part 1: {}", next_grid.len());
+const GLYPH_WIDTH: usize = 4;
+const GLYPH_HEIGHT: usize = 6;
+/// Glyphs are separated by a single empty column
+const GLYPH_SPACING: usize = GLYPH_WIDTH + 1;
+
+/// The capital letters of the 4x6 font, rows concatenated top to bottom
+static FONT: [(char, &str); 16] = [
+    ('A', ".##.#..##..######..##..#"),
+    ('B', "###.#..####.#..##..####."),
+    ('C', ".##.#..##...#...#..#.##."),
+    ('E', "#####...###.#...#...####"),
+    ('F', "#####...###.#...#...#..."),
+    ('G', ".##.#..##...#.###..#.###"),
+    ('H', "#..##..######..##..##..#"),
+    ('J', "..##...#...#...##..#.##."),
+    ('K', "#..##.#.##..#.#.#.#.#..#"),
+    ('L', "#...#...#...#...#...####"),
+    ('O', ".##.#..##..##..##..#.##."),
+    ('P', "###.#..##..####.#...#..."),
+    ('R', "###.#..##..####.#.#.#..#"),
+    ('S', ".####...#....##....####."),
+    ('U', "#..##..##..##..##..#.##."),
+    ('Z', "####...#..#..#..#...####"),
+];
+
+#[derive(Debug)]
+enum OcrError {
+    /// The dots do not form a single line of letters
+    WrongHeight(usize),
+    /// Starting columns of the glyphs that are not in the font
+    UnrecognizedGlyphs(Vec<usize>),
+}
+
+impl Display for OcrError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OcrError::WrongHeight(height) => {
+                write!(f, "expected letters of height {}, found height {}", GLYPH_HEIGHT, height)
+            }
+            OcrError::UnrecognizedGlyphs(columns) => {
+                write!(f, "unrecognized glyphs at columns {:?}", columns)
+            }
         }
+    }
+}
+
+/// Reads the dots as a line of capital letters
+fn recognize_letters(grid: &HashSet<(usize, usize)>) -> Result<String, OcrError> {
+    if grid.is_empty() {
+        return Ok(String::new());
+    }
+
+    let x_min = grid.iter().map(|&(x, _)| x).min().unwrap();
+    let x_max = grid.iter().map(|&(x, _)| x).max().unwrap();
+    let y_min = grid.iter().map(|&(_, y)| y).min().unwrap();
+    let y_max = grid.iter().map(|&(_, y)| y).max().unwrap();
+
+    let height = y_max - y_min + 1;
+    if height != GLYPH_HEIGHT {
+        return Err(OcrError::WrongHeight(height));
+    }
 
-        grid = next_grid;
+    let mut letters = String::new();
+    let mut unrecognized = vec![];
+
+    for gx in (x_min..=x_max).step_by(GLYPH_SPACING) {
+        let glyph: String = (y_min..y_min + GLYPH_HEIGHT)
+            .flat_map(|y| (gx..gx + GLYPH_WIDTH).map(move |x| (x, y)))
+            .map(|p| if grid.contains(&p) { '#' } else { '.' })
+            .collect();
+
+        match FONT.iter().find(|&&(_, g)| g == glyph) {
+            Some(&(letter, _)) => letters.push(letter),
+            None => unrecognized.push(gx - x_min),
+        }
     }
 
-    println!("Part 2:");
-    print_grid(&grid);
+    if unrecognized.is_empty() {
+        Ok(letters)
+    } else {
+        Err(OcrError::UnrecognizedGlyphs(unrecognized))
+    }
 }
 
 fn print_grid(grid: &HashSet<(usize, usize)>) {
@@ -69,13 +150,117 @@ fn print_grid(grid: &HashSet<(usize, usize)>) {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone)]
 enum FoldInstruction {
     Up(usize),
     Left(usize),
 }
 
-fn parse_input(input: &str) -> IResult<&str, (Vec<(usize, usize)>, Vec<FoldInstruction>)> {
+impl Display for FoldInstruction {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FoldInstruction::Up(y) => write!(f, "fold along y={}", y),
+            FoldInstruction::Left(x) => write!(f, "fold along x={}", x),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum FoldError {
+    /// The fold line does not lie strictly inside the paper
+    OutsidePaper(FoldInstruction),
+    /// Dots on the fold line would end up on the crease
+    DotsOnCrease(FoldInstruction, Vec<(usize, usize)>),
+}
+
+impl Display for FoldError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FoldError::OutsidePaper(fold) => write!(f, "{} lies outside the paper", fold),
+            FoldError::DotsOnCrease(fold, dots) => {
+                write!(f, "{} has dots on the crease at {:?}", fold, dots)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Paper {
+    dots: HashSet<(usize, usize)>,
+    width: usize,
+    height: usize,
+}
+
+impl Paper {
+    /// Creates the smallest paper containing all dots
+    fn new(points: Vec<(usize, usize)>) -> Paper {
+        let width = points.iter().map(|&(x, _)| x + 1).max().unwrap_or(0);
+        let height = points.iter().map(|&(_, y)| y + 1).max().unwrap_or(0);
+        Paper {
+            dots: HashSet::from_iter(points),
+            width,
+            height,
+        }
+    }
+
+    /// Folds the paper, moving the origin to the top-left corner of the folded paper. When
+    /// folding past the middle, the folded part sticks out beyond the top (or left) edge, so the
+    /// coordinates of all dots are shifted to keep them non-negative.
+    fn fold(&self, fold: FoldInstruction) -> Result<Paper, FoldError> {
+        let (line, extent) = match fold {
+            FoldInstruction::Up(y) => (y, self.height),
+            FoldInstruction::Left(x) => (x, self.width),
+        };
+        if line == 0 || line + 1 >= extent {
+            return Err(FoldError::OutsidePaper(fold));
+        }
+
+        let mut on_crease: Vec<(usize, usize)> = self.dots.iter()
+            .copied()
+            .filter(|&(x, y)| match fold {
+                FoldInstruction::Up(_) => y == line,
+                FoldInstruction::Left(_) => x == line,
+            })
+            .collect();
+        if !on_crease.is_empty() {
+            on_crease.sort_unstable();
+            return Err(FoldError::DotsOnCrease(fold, on_crease));
+        }
+
+        // Distance by which the folded part sticks out
+        let offset = (extent - 1).saturating_sub(2 * line);
+        let fold_coordinate = |c: usize| if c > line { 2 * line + offset - c } else { c + offset };
+
+        let dots = self.dots.iter()
+            .map(|&(x, y)| match fold {
+                FoldInstruction::Up(_) => (x, fold_coordinate(y)),
+                FoldInstruction::Left(_) => (fold_coordinate(x), y),
+            })
+            .collect();
+
+        let (width, height) = match fold {
+            FoldInstruction::Up(_) => (self.width, line + offset),
+            FoldInstruction::Left(_) => (line + offset, self.height),
+        };
+
+        Ok(Paper { dots, width, height })
+    }
+
+    /// Applies the folds in order, returning the paper after each fold, or the index of the first
+    /// fold that failed
+    fn fold_all(&self, folds: &[FoldInstruction]) -> Result<Vec<Paper>, (usize, FoldError)> {
+        let mut papers: Vec<Paper> = vec![];
+        for (index, &fold) in folds.iter().enumerate() {
+            let paper = papers.last().unwrap_or(self);
+            papers.push(paper.fold(fold).map_err(|e| (index, e))?);
+        }
+        Ok(papers)
+    }
+}
+
+type Manual = (Vec<(usize, usize)>, Vec<FoldInstruction>);
+
+fn parse_input(input: &str) -> IResult<&str, Manual> {
     separated_pair(
         parse_points,
         count(newline, 2),
@@ -94,8 +279,8 @@ fn parse_folds(input: &str) -> IResult<&str, Vec<FoldInstruction>> {
     separated_list1(
         newline,
         preceded(tag("fold along "), alt((
-            map(preceded(tag("x="), parse_number), |x| FoldInstruction::Left(x)),
-            map(preceded(tag("y="), parse_number), |y| FoldInstruction::Up(y)),
+            map(preceded(tag("x="), parse_number), FoldInstruction::Left),
+            map(preceded(tag("y="), parse_number), FoldInstruction::Up),
         ))),
     )(input)
 }
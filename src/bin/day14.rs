@@ -1,17 +1,23 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter};
 use std::fs::File;
 use std::io::Read;
+use std::iter::successors;
 
 use itertools::{Itertools, MinMaxResult};
+use num_bigint::BigUint;
 use nom::bytes::complete::tag;
 use nom::character::complete::{alpha1, anychar, newline};
 use nom::IResult;
 use nom::multi::{count, separated_list1};
 use nom::sequence::{pair, separated_pair};
 
+type Rule = ((char, char), char);
 type Rules = HashMap<(char, char), char>;
-type Counts = HashMap<char, usize>;
-type CountsCache = HashMap<(char, char, usize), Counts>;
+/// Number of occurrences of each element. The polymer roughly doubles in length every step, so
+/// counts overflow any fixed-size integer after a hundred or so steps.
+type Histogram = Vec<(char, BigUint)>;
+type Matrix = Vec<Vec<BigUint>>;
 
 fn main() {
     let mut file = File::open("input/day14.txt").unwrap();
@@ -19,68 +25,294 @@ fn main() {
     file.read_to_string(&mut input).unwrap();
     let input = input.as_str();
 
+    // Usage: day14 [--strict] [--histograms <steps>]
+    let mut missing_rules = MissingRules::Inert;
+    let mut histogram_steps: Option<usize> = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--strict" => missing_rules = MissingRules::Reject,
+            "--histograms" => histogram_steps = Some(args.next().unwrap().parse().unwrap()),
+            _ => panic!("unknown argument: {}", arg),
+        }
+    }
+
     let (_, (template, rules_vec)) = parse_input(input).unwrap();
-    let rules: Rules = Rules::from_iter(rules_vec.into_iter());
+    let rule_set = match RuleSet::new(template, &rules_vec, missing_rules) {
+        Ok(rule_set) => rule_set,
+        Err(e) => {
+            println!("Invalid rules: {}", e);
+            return;
+        }
+    };
+
+    if !rule_set.duplicates.is_empty() {
+        println!("Duplicate rules: {:?}", rule_set.duplicates);
+    }
+    if !rule_set.unreachable_elements.is_empty() {
+        println!("Elements that never appear in the polymer: {:?}", rule_set.unreachable_elements);
+    }
+    if !rule_set.inert_pairs.is_empty() {
+        println!("Pairs without a rule: {:?}", rule_set.inert_pairs);
+    }
+
+    let polymer = Polymer::new(template, &rule_set.rules);
+
+    println!("Part 1: {}", most_minus_least_common(&polymer.histogram(10)));
+    println!("Part 2: {}", most_minus_least_common(&polymer.histogram(40)));
+
+    if let Some(steps) = histogram_steps {
+        for (step, histogram) in polymer.histograms().take(steps + 1).enumerate() {
+            let counts: Vec<String> = histogram.iter()
+                .map(|(element, n)| format!("{}={}", element, n))
+                .collect();
+            println!("Step {}: {}", step, counts.join(" "));
+        }
+    }
+}
 
-    let mut cache: CountsCache = CountsCache::new();
+fn most_minus_least_common(histogram: &Histogram) -> BigUint {
+    // Elements that only appear in rules that never apply do not count as least common
+    let zero = BigUint::default();
+    match histogram.iter().map(|(_, n)| n).filter(|&n| *n > zero).minmax() {
+        MinMaxResult::MinMax(min, max) => max - min,
+        _ => zero,
+    }
+}
+
+/// How to treat pairs that can appear in the polymer but have no insertion rule
+#[derive(Debug, Copy, Clone)]
+enum MissingRules {
+    /// Nothing is inserted between the pair
+    Inert,
+    /// The rules are rejected
+    Reject,
+}
+
+#[derive(Debug)]
+enum RuleError {
+    /// Two rules insert different elements between the same pair
+    Conflicting((char, char), char, char),
+    /// Pairs that can appear in the polymer have no rule
+    Missing(Vec<(char, char)>),
+}
+
+impl Display for RuleError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RuleError::Conflicting((c1, c2), c3, c4) => {
+                write!(f, "{}{} -> {} conflicts with {}{} -> {}", c1, c2, c3, c1, c2, c4)
+            }
+            RuleError::Missing(pairs) => write!(f, "no rules for pairs {:?}", pairs),
+        }
+    }
+}
 
-    println!("Part 1: {}", solve(&rules, &mut cache, template, 10));
-    println!("Part 2: {}", solve(&rules, &mut cache, template, 40));
+/// Validated insertion rules
+#[derive(Debug)]
+struct RuleSet {
+    rules: Rules,
+    /// Rules that appeared more than once (with the same insertion)
+    duplicates: Vec<Rule>,
+    /// Elements used by the rules that can never appear in the polymer
+    unreachable_elements: Vec<char>,
+    /// Pairs that can appear in the polymer but have no rule, these never change
+    inert_pairs: Vec<(char, char)>,
 }
 
-fn merge_counts(counts: &mut Counts, other: Counts) {
-    for (c, n) in other {
-        *counts.entry(c).or_default() += n;
+impl RuleSet {
+    fn new(
+        template: &str,
+        rules_vec: &[Rule],
+        missing_rules: MissingRules,
+    ) -> Result<RuleSet, RuleError> {
+        let mut rules = Rules::new();
+        let mut duplicates = vec![];
+
+        for &(pair, c3) in rules_vec {
+            match rules.insert(pair, c3) {
+                Some(c4) if c4 == c3 => duplicates.push((pair, c3)),
+                Some(c4) => return Err(RuleError::Conflicting(pair, c4, c3)),
+                None => {}
+            }
+        }
+
+        // Find all pairs that can appear in the polymer, starting from the template
+        let mut reachable_pairs: HashSet<(char, char)> = template.chars().tuple_windows().collect();
+        let mut queue: Vec<(char, char)> = reachable_pairs.iter().copied().collect();
+        while let Some((c1, c2)) = queue.pop() {
+            if let Some(&c3) = rules.get(&(c1, c2)) {
+                for next_pair in [(c1, c3), (c3, c2)] {
+                    if reachable_pairs.insert(next_pair) {
+                        queue.push(next_pair);
+                    }
+                }
+            }
+        }
+
+        let reachable_elements: HashSet<char> = reachable_pairs.iter()
+            .flat_map(|&(c1, c2)| [c1, c2])
+            .chain(template.chars())
+            .collect();
+        let unreachable_elements = rules.iter()
+            .flat_map(|(&(c1, c2), &c3)| [c1, c2, c3])
+            .filter(|c| !reachable_elements.contains(c))
+            .sorted()
+            .dedup()
+            .collect();
+
+        let inert_pairs: Vec<(char, char)> = reachable_pairs.into_iter()
+            .filter(|pair| !rules.contains_key(pair))
+            .sorted()
+            .collect();
+
+        if let MissingRules::Reject = missing_rules {
+            if !inert_pairs.is_empty() {
+                return Err(RuleError::Missing(inert_pairs));
+            }
+        }
+
+        Ok(RuleSet { rules, duplicates, unreachable_elements, inert_pairs })
     }
 }
 
-fn process_pair(
-    rules: &Rules,
-    cache: &mut CountsCache,
-    c1: char,
-    c2: char,
-    steps: usize
-) -> Counts {
-    if let Some(cached_result) = cache.get(&(c1, c2, steps)) {
-        return cached_result.clone();
+/// Tracks the polymer as the number of occurrences of each pair of adjacent elements. Since each
+/// pair evolves independently, a step is a linear transition of the pair counts.
+#[derive(Debug)]
+struct Polymer {
+    elements: Vec<char>,
+    /// Number of occurrences of each pair in the template, indexed by `first * n + second`
+    initial_pairs: Vec<BigUint>,
+    /// The last element never changes, and is the only one not counted as the first of a pair
+    last_element: usize,
+    /// For each pair, the pairs it turns into after one step
+    transitions: Vec<Vec<usize>>,
+}
+
+impl Polymer {
+    fn new(template: &str, rules: &Rules) -> Polymer {
+        let mut elements: Vec<char> = template.chars()
+            .chain(rules.iter().flat_map(|(&(c1, c2), &c3)| [c1, c2, c3]))
+            .collect();
+        elements.sort_unstable();
+        elements.dedup();
+
+        let n = elements.len();
+        let index = |c: char| elements.binary_search(&c).unwrap();
+
+        let mut initial_pairs = vec![BigUint::default(); n * n];
+        for (c1, c2) in template.chars().tuple_windows() {
+            initial_pairs[index(c1) * n + index(c2)] += 1u8;
+        }
+
+        let transitions = (0..n * n)
+            .map(|pair| {
+                let (e1, e2) = (pair / n, pair % n);
+                match rules.get(&(elements[e1], elements[e2])) {
+                    Some(&c3) => vec![e1 * n + index(c3), index(c3) * n + e2],
+                    // Pairs without a rule stay as they are
+                    None => vec![pair],
+                }
+            })
+            .collect();
+
+        Polymer {
+            last_element: index(template.chars().last().unwrap()),
+            elements,
+            initial_pairs,
+            transitions,
+        }
+    }
+
+    fn step(&self, pairs: &[BigUint]) -> Vec<BigUint> {
+        let mut next_pairs = vec![BigUint::default(); pairs.len()];
+        for (pair, count) in pairs.iter().enumerate() {
+            for &next_pair in &self.transitions[pair] {
+                next_pairs[next_pair] += count;
+            }
+        }
+        next_pairs
     }
 
-    let mut counts: Counts = Counts::new();
+    fn to_histogram(&self, pairs: &[BigUint]) -> Histogram {
+        let n = self.elements.len();
+        let mut counts = vec![BigUint::default(); n];
+        for (pair, count) in pairs.iter().enumerate() {
+            counts[pair / n] += count;
+        }
+        counts[self.last_element] += 1u8;
 
-    if steps == 0 {
-        *counts.entry(c1).or_default() += 1;
-    } else if let Some(&c3) = rules.get(&(c1, c2)) {
-        merge_counts(&mut counts, process_pair(rules, cache, c1, c3, steps - 1));
-        merge_counts(&mut counts, process_pair(rules, cache, c3, c2, steps - 1));
+        self.elements.iter().copied().zip(counts).collect()
     }
 
-    cache.insert((c1, c2, steps), counts.clone());
+    /// Gets the histogram after each step, starting with the template
+    fn histograms(&self) -> impl Iterator<Item = Histogram> + '_ {
+        successors(Some(self.initial_pairs.clone()), move |pairs| Some(self.step(pairs)))
+            .map(move |pairs| self.to_histogram(&pairs))
+    }
+
+    /// Gets the histogram after the given number of steps, in a logarithmic number of matrix
+    /// multiplications
+    fn histogram(&self, steps: u64) -> Histogram {
+        let size = self.transitions.len();
+
+        let mut transition = vec![vec![BigUint::default(); size]; size];
+        for (pair, next_pairs) in self.transitions.iter().enumerate() {
+            for &next_pair in next_pairs {
+                transition[next_pair][pair] += 1u8;
+            }
+        }
 
-    counts
+        let pairs = multiply_vector(&matrix_power(transition, steps), &self.initial_pairs);
+        self.to_histogram(&pairs)
+    }
 }
 
-fn solve(
-    rules: &Rules,
-    cache: &mut CountsCache,
-    template: &str,
-    steps: usize
-) -> usize {
-    let mut counts: Counts = Counts::new();
-    for (c1, c2) in template.chars().tuple_windows() {
-        merge_counts(&mut counts, process_pair(rules, cache, c1, c2, steps));
+fn multiply_matrices(a: &Matrix, b: &Matrix) -> Matrix {
+    let size = a.len();
+    let zero = BigUint::default();
+    let mut product = vec![vec![zero.clone(); size]; size];
+    for i in 0..size {
+        for k in 0..size {
+            if a[i][k] == zero {
+                continue;
+            }
+            for j in 0..size {
+                if b[k][j] != zero {
+                    product[i][j] += &a[i][k] * &b[k][j];
+                }
+            }
+        }
     }
-    let last_char = template.chars().last().unwrap();
-    *counts.entry(last_char).or_default() += 1;
+    product
+}
 
-    match counts.values().minmax() {
-        MinMaxResult::MinMax(min, max) => {
-            return max - min;
+fn multiply_vector(a: &Matrix, v: &[BigUint]) -> Vec<BigUint> {
+    a.iter()
+        .map(|row| row.iter().zip(v).map(|(x, y)| x * y).sum())
+        .collect()
+}
+
+/// Computes `m^exponent` by repeated squaring
+fn matrix_power(mut m: Matrix, mut exponent: u64) -> Matrix {
+    let size = m.len();
+    let mut result: Matrix = (0..size)
+        .map(|i| (0..size).map(|j| BigUint::from((i == j) as u8)).collect())
+        .collect();
+
+    while exponent > 0 {
+        if exponent & 1 == 1 {
+            result = multiply_matrices(&result, &m);
+        }
+        exponent >>= 1;
+        if exponent > 0 {
+            m = multiply_matrices(&m, &m);
         }
-        _ => panic!()
     }
+    result
 }
 
-fn parse_input(input: &str) -> IResult<&str, (&str, Vec<((char, char), char)>)> {
+fn parse_input(input: &str) -> IResult<&str, (&str, Vec<Rule>)> {
     separated_pair(
         alpha1,
         count(newline, 2),
@@ -88,7 +320,7 @@ fn parse_input(input: &str) -> IResult<&str, (&str, Vec<((char, char), char)>)>
     )(input)
 }
 
-fn parse_rules(input: &str) -> IResult<&str, Vec<((char, char), char)>> {
+fn parse_rules(input: &str) -> IResult<&str, Vec<Rule>> {
     separated_list1(
         newline,
         separated_pair(
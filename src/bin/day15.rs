@@ -1,7 +1,8 @@
-use std::cmp::Ordering;
-use std::collections::BinaryHeap;
+use std::cmp::{max, Ordering};
+use std::collections::{BinaryHeap, HashSet};
 use std::fs::File;
-use std::io::Read;
+use std::io::{Read, Write};
+use std::time::Instant;
 
 use nom::character::complete::{digit1, newline};
 use nom::combinator::map;
@@ -28,82 +29,441 @@ impl PartialOrd for State {
     }
 }
 
+#[derive(Debug, Copy, Clone)]
+enum QueueKind {
+    BinaryHeap,
+    /// Dial's algorithm, works well because risk levels are small integers
+    BucketQueue,
+}
+
+trait PriorityQueue {
+    fn push(&mut self, state: State);
+    fn pop(&mut self) -> Option<State>;
+}
+
+impl PriorityQueue for BinaryHeap<State> {
+    fn push(&mut self, state: State) {
+        BinaryHeap::push(self, state);
+    }
+
+    fn pop(&mut self) -> Option<State> {
+        BinaryHeap::pop(self)
+    }
+}
+
+/// Circular array of buckets, one for each cost. Since every pushed cost is at most the highest
+/// risk level above the last popped cost, that many buckets (plus one) are enough.
+struct BucketQueue {
+    buckets: Vec<Vec<(usize, usize)>>,
+    /// Cost of the bucket the next state is popped from
+    cost: usize,
+    len: usize,
+}
+
+impl BucketQueue {
+    fn new(max_risk: u32) -> Self {
+        BucketQueue {
+            buckets: vec![vec![]; max_risk as usize + 1],
+            cost: 0,
+            len: 0,
+        }
+    }
+}
+
+impl PriorityQueue for BucketQueue {
+    fn push(&mut self, state: State) {
+        debug_assert!(self.cost <= state.cost && state.cost < self.cost + self.buckets.len());
+        let index = state.cost % self.buckets.len();
+        self.buckets[index].push(state.position);
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<State> {
+        if self.len == 0 {
+            return None;
+        }
+
+        loop {
+            let index = self.cost % self.buckets.len();
+            if let Some(position) = self.buckets[index].pop() {
+                self.len -= 1;
+                return Some(State { cost: self.cost, position });
+            }
+            self.cost += 1;
+        }
+    }
+}
+
 fn main() {
     let mut file = File::open("input/day15.txt").unwrap();
     let mut input = String::new();
     file.read_to_string(&mut input).unwrap();
     let input = input.as_str();
 
-    let (_, grid) = parse_input(input).unwrap();
-    let width = grid.len();
-    let height = grid[0].len();
-
-    println!("Part 1: {}", shortest_path_cost(&grid));
-
-    let mut large_grid = vec![vec![0; height * 5]; width * 5];
-    for px in 0..5 {
-        for py in 0..5 {
-            let dx = px * width;
-            let dy = py * height;
-            let dc = (px + py) as u32;
-            for x in 0..width {
-                for y in 0..height {
-                    large_grid[x + dx][y + dy] = (grid[x][y] + dc - 1) % 9 + 1;
+    // Usage: day15 [--tiles <n>] [--modulus <m>] [--diagonal] [--start <x,y>] [--goal <x,y>]
+    //              [--queue <heap|buckets>] [--part <1|2>] [--route] [--distances] [--ppm <path>]
+    //        day15 --bench [<size>]
+    // The start and goal default to the top-left and bottom-right cells of each cave. The
+    // rendering options apply to the cave of the selected part (default 1).
+    let mut tile_factor = 5;
+    let mut risk_modulus = 9;
+    let mut movement = Movement::Orthogonal;
+    let mut queue = QueueKind::BinaryHeap;
+    let mut start = None;
+    let mut goal = None;
+    let mut render_part = 1;
+    let mut print_route = false;
+    let mut print_distances = false;
+    let mut ppm_path = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--tiles" => tile_factor = args.next().unwrap().parse().unwrap(),
+            "--modulus" => {
+                risk_modulus = args.next().unwrap().parse().unwrap();
+                if risk_modulus < 1 {
+                    println!("The risk modulus must be at least 1");
+                    return;
                 }
             }
+            "--diagonal" => movement = Movement::Diagonal,
+            "--start" => start = Some(parse_cell(&args.next().unwrap())),
+            "--goal" => goal = Some(parse_cell(&args.next().unwrap())),
+            "--queue" => queue = match args.next().unwrap().as_str() {
+                "heap" => QueueKind::BinaryHeap,
+                "buckets" => QueueKind::BucketQueue,
+                kind => panic!("unknown queue: {}", kind),
+            },
+            "--bench" => {
+                benchmark(args.next().map_or(5000, |size| size.parse().unwrap()));
+                return;
+            }
+            "--part" => render_part = args.next().unwrap().parse().unwrap(),
+            "--route" => print_route = true,
+            "--distances" => print_distances = true,
+            "--ppm" => ppm_path = args.next(),
+            _ => panic!("unknown argument: {}", arg),
         }
     }
 
-    println!("Part 2: {}", shortest_path_cost(&large_grid));
-}
+    let (_, grid) = parse_input(input).unwrap();
 
-fn shortest_path_cost(grid: &Vec<Vec<u32>>) -> usize {
-    let width = grid.len();
-    let height = grid[0].len();
+    let cave_part1 = Cave { tile: &grid, tile_factor: 1, risk_modulus, movement };
+    let cave_part2 = Cave { tile: &grid, tile_factor, risk_modulus, movement };
 
-    let valid_neighbors = |x: usize, y: usize| -> Vec<(usize, usize)> {
-        let mut neighbors = vec![];
-        if x + 1 < width {
-            neighbors.push((x + 1, y));
+    let find_route = |cave: &Cave| {
+        let start = start.unwrap_or((0, 0));
+        let goal = goal.unwrap_or((cave.width() - 1, cave.height() - 1));
+        lowest_risk_route(cave, start, goal, queue)
+    };
+    let route_part1 = find_route(&cave_part1);
+    let route_part2 = find_route(&cave_part2);
+
+    for (part, route) in [(1, &route_part1), (2, &route_part2)] {
+        match route {
+            Some(route) => println!("Part {}: {}", part, route.cost),
+            None => println!("Part {}: no route", part),
         }
-        if y + 1 < height {
-            neighbors.push((x, y + 1));
+    }
+
+    let (cave, route) = match render_part {
+        1 => (&cave_part1, &route_part1),
+        _ => (&cave_part2, &route_part2),
+    };
+    let route_cells = route.as_ref().map_or(&[][..], |route| &route.cells);
+
+    if print_route {
+        print!("{}", render_route(cave, route_cells));
+    }
+
+    if print_distances {
+        for row in distance_field(cave, start.unwrap_or((0, 0)), queue) {
+            let row: Vec<String> = row.iter()
+                .map(|d| d.map_or("-".to_string(), |d| d.to_string()))
+                .collect();
+            println!("{}", row.join(" "));
         }
-        if x > 0 {
-            neighbors.push((x - 1, y));
+    }
+
+    if let Some(path) = ppm_path {
+        let mut file = File::create(path).unwrap();
+        file.write_all(route_to_ppm(cave, route_cells).as_bytes()).unwrap();
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+enum Movement {
+    /// Up, down, left and right
+    Orthogonal,
+    /// Also diagonally
+    Diagonal,
+}
+
+/// The full cave, made up of copies of the scanned tile whose risk levels increase by one for
+/// each tile to the right or down. Risk levels are computed on demand, and the scanned tile
+/// itself keeps its original risk levels.
+#[derive(Debug)]
+struct Cave<'a> {
+    tile: &'a [Vec<u32>],
+    /// Number of tiles in each direction
+    tile_factor: usize,
+    /// Risk levels of the copies above this wrap back around to 1
+    risk_modulus: u32,
+    movement: Movement,
+}
+
+impl<'a> Cave<'a> {
+    fn width(&self) -> usize {
+        self.tile.len() * self.tile_factor
+    }
+
+    fn height(&self) -> usize {
+        self.tile[0].len() * self.tile_factor
+    }
+
+    fn contains(&self, (x, y): (usize, usize)) -> bool {
+        x < self.width() && y < self.height()
+    }
+
+    fn risk(&self, x: usize, y: usize) -> u32 {
+        let tile_width = self.tile.len();
+        let tile_height = self.tile[0].len();
+        let increase = (x / tile_width + y / tile_height) as u32;
+        let risk = self.tile[x % tile_width][y % tile_height];
+        if increase == 0 {
+            risk
+        } else {
+            (risk + increase - 1) % self.risk_modulus + 1
         }
-        if y > 0 {
-            neighbors.push((x, y - 1));
+    }
+
+    /// The highest risk level of any cell
+    fn max_risk(&self) -> u32 {
+        let max_tile_risk = self.tile.iter().flatten().copied().max().unwrap_or(0);
+        if self.tile_factor > 1 {
+            // The copies wrap around, so they can reach the modulus but never exceed it
+            max(max_tile_risk, self.risk_modulus)
+        } else {
+            max_tile_risk
+        }
+    }
+
+    fn neighbors(&self, x: usize, y: usize) -> Vec<(usize, usize)> {
+        let mut neighbors = vec![];
+        for dx in -1..=1_isize {
+            for dy in -1..=1_isize {
+                let is_diagonal = dx != 0 && dy != 0;
+                let is_allowed = match self.movement {
+                    Movement::Orthogonal => !is_diagonal,
+                    Movement::Diagonal => true,
+                };
+                if (dx == 0 && dy == 0) || !is_allowed {
+                    continue;
+                }
+
+                let nx = x.wrapping_add(dx as usize);
+                let ny = y.wrapping_add(dy as usize);
+                if self.contains((nx, ny)) {
+                    neighbors.push((nx, ny));
+                }
+            }
         }
         neighbors
-    };
+    }
+}
+
+#[derive(Debug)]
+struct Route {
+    cost: usize,
+    /// Cells from start to goal, both included
+    cells: Vec<(usize, usize)>,
+}
+
+fn lowest_risk_route(
+    cave: &Cave,
+    start: (usize, usize),
+    goal: (usize, usize),
+    queue: QueueKind,
+) -> Option<Route> {
+    if !cave.contains(start) || !cave.contains(goal) {
+        return None;
+    }
+
+    let search = search(cave, start, Some(goal), queue);
+
+    let cost = search.dist[goal.0][goal.1];
+    if cost == usize::MAX {
+        return None;
+    }
+
+    let mut cells = vec![goal];
+    while let Some(prev) = search.prev_cell(*cells.last().unwrap()) {
+        cells.push(prev);
+    }
+    cells.reverse();
+
+    Some(Route { cost, cells })
+}
+
+/// Gets the lowest total risk of reaching each cell from `start`, `None` for unreachable cells
+fn distance_field(
+    cave: &Cave,
+    start: (usize, usize),
+    queue: QueueKind,
+) -> Vec<Vec<Option<usize>>> {
+    search(cave, start, None, queue).dist.into_iter()
+        .map(|row| {
+            row.into_iter()
+                .map(|d| if d == usize::MAX { None } else { Some(d) })
+                .collect()
+        })
+        .collect()
+}
+
+struct Search {
+    dist: Vec<Vec<usize>>,
+    /// The direction of the previous cell on the lowest-risk route to each cell, a byte each so
+    /// that large caves fit in memory
+    prev: Vec<Vec<u8>>,
+}
+
+impl Search {
+    /// Marks cells without a previous cell
+    const NO_PREV: u8 = u8::MAX;
+
+    /// Encodes the direction from `cell` to its neighbor `prev` as `3 * (dx + 1) + (dy + 1)`
+    fn direction(cell: (usize, usize), prev: (usize, usize)) -> u8 {
+        ((prev.0 + 1 - cell.0) * 3 + (prev.1 + 1 - cell.1)) as u8
+    }
+
+    fn prev_cell(&self, (x, y): (usize, usize)) -> Option<(usize, usize)> {
+        match self.prev[x][y] {
+            Search::NO_PREV => None,
+            direction => {
+                let (dx, dy) = ((direction / 3) as usize, (direction % 3) as usize);
+                Some((x + dx - 1, y + dy - 1))
+            }
+        }
+    }
+}
+
+fn search(
+    cave: &Cave,
+    start: (usize, usize),
+    goal: Option<(usize, usize)>,
+    queue: QueueKind,
+) -> Search {
+    match queue {
+        QueueKind::BinaryHeap => dijkstra(cave, start, goal, BinaryHeap::new()),
+        QueueKind::BucketQueue => dijkstra(cave, start, goal, BucketQueue::new(cave.max_risk())),
+    }
+}
 
-    let mut dist = vec![vec![usize::MAX; height]; width];
-    let mut heap = BinaryHeap::new();
+/// Runs Dijkstra's algorithm from `start`, stopping early once `goal` is reached
+fn dijkstra<Q: PriorityQueue>(
+    cave: &Cave,
+    start: (usize, usize),
+    goal: Option<(usize, usize)>,
+    mut queue: Q,
+) -> Search {
+    let mut dist = vec![vec![usize::MAX; cave.height()]; cave.width()];
+    let mut prev = vec![vec![Search::NO_PREV; cave.height()]; cave.width()];
 
-    dist[0][0] = 0;
-    heap.push(State { cost: 0, position: (0, 0) });
+    dist[start.0][start.1] = 0;
+    queue.push(State { cost: 0, position: start });
 
-    while let Some(State { cost, position: (x, y) }) = heap.pop() {
-        if x == width - 1 && y == height - 1 {
-            return cost;
+    while let Some(State { cost, position: (x, y) }) = queue.pop() {
+        if Some((x, y)) == goal {
+            break;
         }
 
         if cost > dist[x][y] {
             continue;
         }
 
-        for (nx, ny) in valid_neighbors(x, y) {
-            let next = State { cost: cost + grid[nx][ny] as usize, position: (nx, ny) };
+        for (nx, ny) in cave.neighbors(x, y) {
+            let next = State { cost: cost + cave.risk(nx, ny) as usize, position: (nx, ny) };
 
             if next.cost < dist[nx][ny] {
-                heap.push(next);
+                queue.push(next);
                 dist[nx][ny] = next.cost;
+                prev[nx][ny] = Search::direction((nx, ny), (x, y));
+            }
+        }
+    }
+
+    Search { dist, prev }
+}
+
+/// Renders the cave with only the risk levels along the route shown
+fn render_route(cave: &Cave, route: &[(usize, usize)]) -> String {
+    let route: HashSet<&(usize, usize)> = route.iter().collect();
+
+    let mut rendered = String::new();
+    for x in 0..cave.width() {
+        for y in 0..cave.height() {
+            if route.contains(&(x, y)) {
+                rendered.push(char::from_digit(cave.risk(x, y), 36).unwrap_or('#'));
+            } else {
+                rendered.push('.');
             }
         }
+        rendered.push('\n');
+    }
+    rendered
+}
+
+/// Renders the cave as a plain PPM image, with risk levels in grey (darker is riskier) and the
+/// route in red
+fn route_to_ppm(cave: &Cave, route: &[(usize, usize)]) -> String {
+    let route: HashSet<&(usize, usize)> = route.iter().collect();
+    let max_risk = cave.max_risk();
+
+    let mut ppm = format!("P3\n{} {}\n255\n", cave.height(), cave.width());
+    for x in 0..cave.width() {
+        let pixels: Vec<String> = (0..cave.height())
+            .map(|y| {
+                if route.contains(&(x, y)) {
+                    "255 0 0".to_string()
+                } else {
+                    let grey = 255 - cave.risk(x, y) * 255 / (max_risk + 1);
+                    format!("{} {} {}", grey, grey, grey)
+                }
+            })
+            .collect();
+        ppm.push_str(&pixels.join(" "));
+        ppm.push('\n');
     }
+    ppm
+}
+
+/// Times both queues on a generated cave of `size` by `size` cells (5000 by default)
+fn benchmark(size: usize) {
+    // Xorshift, good enough for generating risk levels
+    let mut seed: u64 = 0x2545_f491_4f6c_dd1d;
+    let mut next_risk = || {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        (seed % 9) as u32 + 1
+    };
+    let grid: Vec<Vec<u32>> = (0..size)
+        .map(|_| (0..size).map(|_| next_risk()).collect())
+        .collect();
+
+    let cave = Cave { tile: &grid, tile_factor: 1, risk_modulus: 9, movement: Movement::Orthogonal };
+    let goal = (size - 1, size - 1);
+
+    for queue in [QueueKind::BinaryHeap, QueueKind::BucketQueue] {
+        let start_time = Instant::now();
+        let route = lowest_risk_route(&cave, (0, 0), goal, queue).unwrap();
+        println!("{:?}: cost {} in {:?}", queue, route.cost, start_time.elapsed());
+    }
+}
 
-    panic!();
+fn parse_cell(s: &str) -> (usize, usize) {
+    let (x, y) = s.split_once(',').unwrap();
+    (x.parse().unwrap(), y.parse().unwrap())
 }
 
 fn parse_input(input: &str) -> IResult<&str, Vec<Vec<u32>>> {
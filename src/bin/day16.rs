@@ -1,25 +1,152 @@
+use std::fmt::{Display, Formatter};
 use std::fs::File;
-use std::io::Read;
+use std::io::{BufReader, Bytes, Read};
+
+use num_bigint::BigUint;
 
 fn main() {
     let file = File::open("input/day16.txt").unwrap();
 
-    let mut bits: Vec<bool> = vec![];
+    let root_packet = match decode_transmission(file) {
+        Ok(packet) => packet,
+        Err(e) => {
+            println!("Decoding failed: {}", e);
+            return;
+        }
+    };
+
+    println!("Part 1: {}", sum_version_numbers(&root_packet));
+    match evaluate_packet(&root_packet) {
+        Ok(value) => println!("Part 2: {}", value),
+        Err(e) => println!("Part 2: {}", e),
+    }
+
+    // Usage: day16 [--print] [--annotate] [--trace] [--encode <length|count>]
+    // Re-encodes the transmission using the given length type for all operators.
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--print" => println!("{}", root_packet),
+            "--annotate" => println!("{:#}", root_packet),
+            "--trace" => {
+                let (trace, result) = evaluate_packet_traced(&root_packet);
+                for step in trace {
+                    println!("{}", step);
+                }
+                if let Err(e) = result {
+                    println!("{}", e);
+                }
+            }
+            "--encode" => {
+                let length_type = match args.next().unwrap().as_str() {
+                    "length" => LengthType::TotalLength,
+                    "count" => LengthType::PacketCount,
+                    length_type => panic!("unknown length type: {}", length_type),
+                };
+
+                let hex = match encode_transmission(&root_packet, length_type) {
+                    Ok(hex) => hex,
+                    Err(e) => {
+                        println!("Encoding failed: {}", e);
+                        continue;
+                    }
+                };
+                println!("Encoded: {}", hex);
 
-    for b in file.bytes() {
-        let b = b.unwrap();
-        let bs = &[b];
-        let s = std::str::from_utf8(bs).unwrap();
-        if let Ok(n) = u8::from_str_radix(s, 16) {
-            for i in [8, 4, 2, 1] {
-                bits.push(n & i != 0);
+                let decoded_packet = decode_transmission(hex.as_bytes()).unwrap();
+                let is_round_trip = decoded_packet == root_packet;
+                println!("Round trip: {}", if is_round_trip { "ok" } else { "mismatch" });
             }
+            _ => panic!("unknown argument: {}", arg),
         }
     }
+}
 
-    let (root_packet, _bits) =  decode_packet(bits.as_slice());
-    println!("Part 1: {}", sum_version_numbers(&root_packet));
-    println!("Part 2: {}", evaluate_packet(&root_packet));
+/// Indices of the sub-packets leading from the outermost packet to a packet
+#[derive(Debug, Clone, Default)]
+struct PacketPath(Vec<usize>);
+
+impl Display for PacketPath {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "root")?;
+        for index in &self.0 {
+            write!(f, ".{}", index)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+enum DecodeErrorKind {
+    Io(std::io::Error),
+    InvalidHexDigit(char),
+    /// The transmission ended in the middle of a packet
+    Truncated,
+    /// The sub-packets of an operator did not add up to its declared length in bits
+    LengthMismatch,
+}
+
+#[derive(Debug)]
+struct DecodeError {
+    kind: DecodeErrorKind,
+    /// Offset in bits into the transmission
+    offset: usize,
+    /// Path to the packet being decoded
+    path: PacketPath,
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.kind {
+            DecodeErrorKind::Io(e) => write!(f, "read failed ({})", e)?,
+            DecodeErrorKind::InvalidHexDigit(c) => write!(f, "invalid hex digit {:?}", c)?,
+            DecodeErrorKind::Truncated => write!(f, "truncated transmission")?,
+            DecodeErrorKind::LengthMismatch => write!(f, "sub-packets exceed the declared length")?,
+        }
+        write!(f, " at bit {} in packet {}", self.offset, self.path)
+    }
+}
+
+#[derive(Debug)]
+struct EncodeError {
+    length_type: LengthType,
+    /// The number of sub-packets or their length in bits, which does not fit the length type
+    length: usize,
+    /// Path to the operator packet
+    path: PacketPath,
+}
+
+impl Display for EncodeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.length_type {
+            LengthType::TotalLength => write!(f, "{} bits of sub-packets", self.length)?,
+            LengthType::PacketCount => write!(f, "{} sub-packets", self.length)?,
+        }
+        write!(
+            f,
+            " do not fit in {} bits in packet {}",
+            self.length_type.num_bits(), self.path,
+        )
+    }
+}
+
+#[derive(Debug)]
+struct EvalError {
+    operator: Operator,
+    num_operands: usize,
+    /// Offset in bits of the operator packet
+    offset: usize,
+    path: PacketPath,
+}
+
+impl Display for EvalError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:?} cannot take {} operands, at bit {} in packet {}",
+            self.operator, self.num_operands, self.offset, self.path,
+        )
+    }
 }
 
 fn sum_version_numbers(packet: &Packet) -> usize {
@@ -32,23 +159,85 @@ fn sum_version_numbers(packet: &Packet) -> usize {
     sum
 }
 
-fn evaluate_packet(packet: &Packet) -> usize {
+/// The evaluation of a single operator
+#[derive(Debug)]
+struct TraceStep {
+    path: PacketPath,
+    operator: Operator,
+    inputs: Vec<BigUint>,
+    output: BigUint,
+}
+
+impl Display for TraceStep {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let inputs: Vec<String> = self.inputs.iter().map(|n| n.to_string()).collect();
+        if self.operator.is_comparison() {
+            let expression = inputs.join(&format!(" {} ", self.operator));
+            write!(f, "{}: {} = {}", self.path, expression, self.output)
+        } else {
+            write!(f, "{}: {}({}) = {}", self.path, self.operator, inputs.join(", "), self.output)
+        }
+    }
+}
+
+fn evaluate_packet(packet: &Packet) -> Result<BigUint, EvalError> {
+    evaluate_packet_at(packet, &mut PacketPath::default(), &mut vec![])
+}
+
+/// Evaluates the packet, also returning the operators evaluated before any error occurred
+fn evaluate_packet_traced(packet: &Packet) -> (Vec<TraceStep>, Result<BigUint, EvalError>) {
+    let mut trace = vec![];
+    let result = evaluate_packet_at(packet, &mut PacketPath::default(), &mut trace);
+    (trace, result)
+}
+
+/// Evaluates the packet, recording each evaluated operator in `trace` (innermost first)
+fn evaluate_packet_at(
+    packet: &Packet,
+    path: &mut PacketPath,
+    trace: &mut Vec<TraceStep>,
+) -> Result<BigUint, EvalError> {
     match &packet.packet_type {
-        PacketType::Literal(value) => *value,
+        PacketType::Literal(value) => Ok(value.clone()),
         PacketType::Operator(operator, sub_packets) => {
-            let values: Vec<usize> = sub_packets.iter()
-                .map(evaluate_packet)
-                .collect();
+            let is_valid_arity = if operator.is_comparison() {
+                sub_packets.len() == 2
+            } else {
+                !sub_packets.is_empty()
+            };
+            if !is_valid_arity {
+                return Err(EvalError {
+                    operator: *operator,
+                    num_operands: sub_packets.len(),
+                    offset: packet.offset,
+                    path: path.clone(),
+                });
+            }
+
+            let mut values = vec![];
+            for (index, sub_packet) in sub_packets.iter().enumerate() {
+                path.0.push(index);
+                values.push(evaluate_packet_at(sub_packet, path, trace)?);
+                path.0.pop();
+            }
 
-            match operator {
+            let output = match operator {
                 Operator::Sum => values.iter().sum(),
                 Operator::Product => values.iter().product(),
-                Operator::Minimum => values.into_iter().min().unwrap(),
-                Operator::Maximum => values.into_iter().max().unwrap(),
-                Operator::GreaterThan => (values[0] > values[1]) as usize,
-                Operator::LessThan => (values[0] < values[1]) as usize,
-                Operator::EqualTo => (values[0] == values[1]) as usize
-            }
+                Operator::Minimum => values.iter().min().unwrap().clone(),
+                Operator::Maximum => values.iter().max().unwrap().clone(),
+                Operator::GreaterThan => BigUint::from((values[0] > values[1]) as u8),
+                Operator::LessThan => BigUint::from((values[0] < values[1]) as u8),
+                Operator::EqualTo => BigUint::from((values[0] == values[1]) as u8),
+            };
+
+            trace.push(TraceStep {
+                path: path.clone(),
+                operator: *operator,
+                inputs: values,
+                output: output.clone(),
+            });
+            Ok(output)
         },
     }
 }
@@ -57,15 +246,24 @@ fn evaluate_packet(packet: &Packet) -> usize {
 struct Packet {
     version: usize,
     packet_type: PacketType,
+    /// Offset in bits into the transmission it was decoded from
+    offset: usize,
 }
 
-#[derive(Debug)]
+/// Packets are equal if they have the same contents, regardless of where they were decoded from
+impl PartialEq for Packet {
+    fn eq(&self, other: &Self) -> bool {
+        self.version == other.version && self.packet_type == other.packet_type
+    }
+}
+
+#[derive(Debug, PartialEq)]
 enum PacketType {
-    Literal(usize),
+    Literal(BigUint),
     Operator(Operator, Vec<Packet>),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 enum Operator {
     Sum,
     Product,
@@ -76,95 +274,350 @@ enum Operator {
     EqualTo,
 }
 
-fn bits_to_usize(bits: &[bool]) -> usize {
-    let mut n = 0;
-    for p in 0..bits.len() {
-        n = n << 1;
-        if bits[p] { n += 1; }
+impl Operator {
+    /// Comparisons are written infix, the other operators as function calls
+    fn is_comparison(&self) -> bool {
+        matches!(self, Operator::GreaterThan | Operator::LessThan | Operator::EqualTo)
     }
-    return n;
 }
 
-fn decode_packet(bits: &[bool]) -> (Packet, &[bool]) {
-    let (version, bits) = bits.split_at(3);
-    let version = bits_to_usize(version);
+impl Display for Operator {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Operator::Sum => "sum",
+            Operator::Product => "product",
+            Operator::Minimum => "min",
+            Operator::Maximum => "max",
+            Operator::GreaterThan => ">",
+            Operator::LessThan => "<",
+            Operator::EqualTo => "==",
+        };
+        write!(f, "{}", s)
+    }
+}
 
-    let (type_id, bits) = bits.split_at(3);
-    let type_id = bits_to_usize(type_id);
+/// Renders the packet as an expression, like `sum(3, product(2, 5) > 7)`. The alternate flag
+/// (`{:#}`) annotates each packet with its version and bit offset, like `3[v1 @22]`.
+impl Display for Packet {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        self.fmt_expression(f, false)
+    }
+}
 
-    let (packet_type, bits) = match type_id {
-        4 => decode_literal(bits),
-        type_id => decode_operator(type_id, bits),
-    };
+impl Packet {
+    fn fmt_expression(&self, f: &mut Formatter<'_>, is_operand: bool) -> std::fmt::Result {
+        match &self.packet_type {
+            PacketType::Literal(value) => write!(f, "{}", value)?,
+            PacketType::Operator(operator, sub_packets) if operator.is_comparison() => {
+                // Nested comparisons need parentheses, and so do annotated ones
+                let needs_parentheses = is_operand || f.alternate();
+                if needs_parentheses {
+                    write!(f, "(")?;
+                }
+                for (index, sub_packet) in sub_packets.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, " {} ", operator)?;
+                    }
+                    sub_packet.fmt_expression(f, true)?;
+                }
+                if needs_parentheses {
+                    write!(f, ")")?;
+                }
+            }
+            PacketType::Operator(operator, sub_packets) => {
+                write!(f, "{}(", operator)?;
+                for (index, sub_packet) in sub_packets.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, ", ")?;
+                    }
+                    sub_packet.fmt_expression(f, false)?;
+                }
+                write!(f, ")")?;
+            }
+        }
 
-    return (
-        Packet { version, packet_type },
-        bits,
-    );
+        if f.alternate() {
+            write!(f, "[v{} @{}]", self.version, self.offset)?;
+        }
+        Ok(())
+    }
 }
 
-fn decode_literal(mut bits: &[bool]) -> (PacketType, &[bool]) {
-    let mut value_bits = vec![];
+fn bits_to_usize(bits: &[bool]) -> usize {
+    bits.iter().fold(0, |n, &bit| n << 1 | bit as usize)
+}
 
-    loop {
-        let (prefix, _bits) = bits.split_at(1);
-        bits = _bits;
-        let prefix = prefix[0];
+/// Decodes the outermost packet from hex digits, ignoring any padding after it. The transmission
+/// ends at the first whitespace or at the end of the input.
+fn decode_transmission<R: Read>(reader: R) -> Result<Packet, DecodeError> {
+    let mut decoder = Decoder { reader: BitReader::new(reader), path: PacketPath::default() };
+    decoder.decode_packet()
+}
 
-        let (chunk, _bits) = bits.split_at(4);
-        bits = _bits;
-        value_bits.extend_from_slice(chunk);
+/// Reads bits from hex digits as they are needed, buffering at most 64 bits at a time
+struct BitReader<R> {
+    bytes: Bytes<BufReader<R>>,
+    /// Bits read from the input but not yet consumed, in the low `buffered` bits
+    buffer: u64,
+    buffered: usize,
+    /// Number of bits consumed so far
+    offset: usize,
+    /// Whether the end of the transmission has been reached
+    is_finished: bool,
+}
 
-        if !prefix {
-            break;
+impl<R: Read> BitReader<R> {
+    fn new(reader: R) -> Self {
+        BitReader {
+            bytes: BufReader::new(reader).bytes(),
+            buffer: 0,
+            buffered: 0,
+            offset: 0,
+            is_finished: false,
         }
     }
 
-    let value = bits_to_usize(value_bits.as_slice());
-    (PacketType::Literal(value), bits)
+    /// Buffers hex digits until at least `len` bits are available or the transmission ends
+    fn fill(&mut self, len: usize) -> Result<(), (DecodeErrorKind, usize)> {
+        while self.buffered < len && !self.is_finished {
+            let offset = self.offset + self.buffered;
+            let byte = match self.bytes.next() {
+                Some(byte) => byte.map_err(|e| (DecodeErrorKind::Io(e), offset))?,
+                None => {
+                    self.is_finished = true;
+                    break;
+                }
+            };
+
+            let c = byte as char;
+            if c.is_ascii_whitespace() {
+                self.is_finished = true;
+            } else {
+                let digit = c.to_digit(16)
+                    .ok_or((DecodeErrorKind::InvalidHexDigit(c), offset))?;
+                self.buffer = self.buffer << 4 | digit as u64;
+                self.buffered += 4;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads up to 32 bits as an unsigned integer, most significant bit first
+    fn read(&mut self, len: usize) -> Result<usize, (DecodeErrorKind, usize)> {
+        assert!(len <= 32, "cannot read {} bits at once", len);
+        self.fill(len)?;
+        if self.buffered < len {
+            return Err((DecodeErrorKind::Truncated, self.offset));
+        }
+
+        self.buffered -= len;
+        self.offset += len;
+        let n = self.buffer >> self.buffered & ((1 << len) - 1);
+        // Drop the consumed bits so the buffer never overflows
+        self.buffer &= (1 << self.buffered) - 1;
+        Ok(n as usize)
+    }
+}
+
+struct Decoder<R> {
+    reader: BitReader<R>,
+    path: PacketPath,
 }
 
-fn decode_operator(type_id: usize, bits: &[bool]) -> (PacketType, &[bool]) {
-    let (length_type_id, mut bits) = bits.split_at(1);
-    let length_type_id = length_type_id[0];
+impl<R: Read> Decoder<R> {
+    fn error(&self, kind: DecodeErrorKind, offset: usize) -> DecodeError {
+        DecodeError { kind, offset, path: self.path.clone() }
+    }
+
+    fn offset(&self) -> usize {
+        self.reader.offset
+    }
+
+    fn read(&mut self, len: usize) -> Result<usize, DecodeError> {
+        self.reader.read(len).map_err(|(kind, offset)| self.error(kind, offset))
+    }
+
+    fn decode_packet(&mut self) -> Result<Packet, DecodeError> {
+        let offset = self.offset();
+        let version = self.read(3)?;
+        let type_id = self.read(3)?;
+
+        let packet_type = match type_id {
+            4 => self.decode_literal()?,
+            type_id => self.decode_operator(type_id)?,
+        };
+
+        Ok(Packet { version, packet_type, offset })
+    }
+
+    fn decode_literal(&mut self) -> Result<PacketType, DecodeError> {
+        // Shifting a growing number for every group would take quadratic time on huge literals
+        let mut groups = vec![];
+
+        loop {
+            let prefix = self.read(1)?;
+            groups.push(self.read(4)? as u8);
+
+            if prefix == 0 {
+                break;
+            }
+        }
+
+        Ok(PacketType::Literal(BigUint::from_radix_be(&groups, 16).unwrap()))
+    }
+
+    fn decode_sub_packet(&mut self, sub_packets: &mut Vec<Packet>) -> Result<(), DecodeError> {
+        self.path.0.push(sub_packets.len());
+        let packet = self.decode_packet()?;
+        self.path.0.pop();
 
-    let mut sub_packets = vec![];
+        sub_packets.push(packet);
+        Ok(())
+    }
+
+    fn decode_operator(&mut self, type_id: usize) -> Result<PacketType, DecodeError> {
+        let length_type_id = self.read(1)?;
+
+        let mut sub_packets = vec![];
 
-    if !length_type_id {
-        let (remaining_length, _bits) = bits.split_at(15);
-        bits = _bits;
-        let mut remaining_length = bits_to_usize(remaining_length);
+        if length_type_id == 0 {
+            let length = self.read(15)?;
+            let end = self.offset() + length;
 
-        while remaining_length != 0 {
-            let (packet, _bits) = decode_packet(bits);
-            remaining_length -= bits.len() - _bits.len();
-            bits = _bits;
+            while self.offset() < end {
+                self.decode_sub_packet(&mut sub_packets)?;
+            }
+            if self.offset() != end {
+                return Err(self.error(DecodeErrorKind::LengthMismatch, end));
+            }
+        } else {
+            let num_packets = self.read(11)?;
 
-            sub_packets.push(packet);
+            for _ in 0..num_packets {
+                self.decode_sub_packet(&mut sub_packets)?;
+            }
         }
-    } else {
-        let (num_packets, _bits) = bits.split_at(11);
-        bits = _bits;
-        let num_packets = bits_to_usize(num_packets);
 
-        for _ in 0..num_packets {
-            let (packet, _bits) = decode_packet(bits);
-            bits = _bits;
+        let operator = match type_id {
+            0 => Operator::Sum,
+            1 => Operator::Product,
+            2 => Operator::Minimum,
+            3 => Operator::Maximum,
+            5 => Operator::GreaterThan,
+            6 => Operator::LessThan,
+            7 => Operator::EqualTo,
+            // Type IDs are 3 bits and 4 is a literal
+            _ => unreachable!(),
+        };
+
+        Ok(PacketType::Operator(operator, sub_packets))
+    }
+}
 
-            sub_packets.push(packet);
+/// How an operator packet encodes the extent of its sub-packets
+#[derive(Debug, Copy, Clone)]
+enum LengthType {
+    /// Total length of the sub-packets in bits, as 15 bits
+    TotalLength,
+    /// Number of sub-packets, as 11 bits
+    PacketCount,
+}
+
+impl LengthType {
+    fn num_bits(&self) -> usize {
+        match self {
+            LengthType::TotalLength => 15,
+            LengthType::PacketCount => 11,
         }
     }
+}
+
+/// Encodes the packet as hex digits, padding the last digit with zeros. Fails if an operator has
+/// too many sub-packets for the length type.
+fn encode_transmission(packet: &Packet, length_type: LengthType) -> Result<String, EncodeError> {
+    let mut bits = vec![];
+    encode_packet(packet, length_type, &mut PacketPath::default(), &mut bits)?;
 
-    let operator = match type_id {
-        0 => Operator::Sum,
-        1 => Operator::Product,
-        2 => Operator::Minimum,
-        3 => Operator::Maximum,
-        5 => Operator::GreaterThan,
-        6 => Operator::LessThan,
-        7 => Operator::EqualTo,
-        _ => unreachable!(),
+    let hex = bits.chunks(4)
+        .map(|chunk| {
+            let mut digit = [false; 4];
+            digit[..chunk.len()].copy_from_slice(chunk);
+            char::from_digit(bits_to_usize(&digit) as u32, 16).unwrap().to_ascii_uppercase()
+        })
+        .collect();
+    Ok(hex)
+}
+
+fn usize_to_bits(n: usize, len: usize, bits: &mut Vec<bool>) {
+    assert!(len >= usize::BITS as usize || n >> len == 0, "{} does not fit in {} bits", n, len);
+    for i in (0..len).rev() {
+        bits.push(i < usize::BITS as usize && n >> i & 1 == 1);
+    }
+}
+
+fn encode_packet(
+    packet: &Packet,
+    length_type: LengthType,
+    path: &mut PacketPath,
+    bits: &mut Vec<bool>,
+) -> Result<(), EncodeError> {
+    usize_to_bits(packet.version, 3, bits);
+
+    match &packet.packet_type {
+        PacketType::Literal(value) => {
+            usize_to_bits(4, 3, bits);
+            encode_literal(value, bits);
+        }
+        PacketType::Operator(operator, sub_packets) => {
+            let type_id = match operator {
+                Operator::Sum => 0,
+                Operator::Product => 1,
+                Operator::Minimum => 2,
+                Operator::Maximum => 3,
+                Operator::GreaterThan => 5,
+                Operator::LessThan => 6,
+                Operator::EqualTo => 7,
+            };
+            usize_to_bits(type_id, 3, bits);
+            encode_operator(sub_packets, length_type, path, bits)?;
+        }
+    }
+    Ok(())
+}
+
+fn encode_literal(value: &BigUint, bits: &mut Vec<bool>) {
+    // One 4-bit group per hex digit, which uses as few groups as possible but at least one
+    let groups = value.to_radix_be(16);
+
+    for (index, &group) in groups.iter().enumerate() {
+        bits.push(index + 1 < groups.len());
+        usize_to_bits(group as usize, 4, bits);
+    }
+}
+
+fn encode_operator(
+    sub_packets: &[Packet],
+    length_type: LengthType,
+    path: &mut PacketPath,
+    bits: &mut Vec<bool>,
+) -> Result<(), EncodeError> {
+    let mut sub_bits = vec![];
+    for (index, sub_packet) in sub_packets.iter().enumerate() {
+        path.0.push(index);
+        encode_packet(sub_packet, length_type, path, &mut sub_bits)?;
+        path.0.pop();
+    }
+
+    let (length_type_id, length) = match length_type {
+        LengthType::TotalLength => (false, sub_bits.len()),
+        LengthType::PacketCount => (true, sub_packets.len()),
     };
+    if length >> length_type.num_bits() != 0 {
+        return Err(EncodeError { length_type, length, path: path.clone() });
+    }
 
-    (PacketType::Operator(operator, sub_packets), bits)
+    bits.push(length_type_id);
+    usize_to_bits(length, length_type.num_bits(), bits);
+    bits.extend(sub_bits);
+    Ok(())
 }
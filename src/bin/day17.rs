@@ -1,4 +1,5 @@
-use std::cmp::max;
+use std::cmp::{max, min};
+use std::fmt::{Display, Formatter};
 use std::fs::File;
 use std::io::Read;
 
@@ -14,44 +15,324 @@ fn main() {
     file.read_to_string(&mut input).unwrap();
     let input = input.as_str();
 
-    let (_, ((x_min, x_max), (y_min, y_max))) = parse_input(input).unwrap();
+    // Usage: day17 [--list] [--trajectory <vx,vy>]
+    let mut list = false;
+    let mut launch_velocity = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--list" => list = true,
+            "--trajectory" => {
+                let velocity = args.next().unwrap();
+                let (vx, vy) = velocity.split_once(',').unwrap();
+                launch_velocity = Some((vx.parse().unwrap(), vy.parse().unwrap()));
+            }
+            _ => panic!("unknown argument: {}", arg),
+        }
+    }
 
-    let mut overall_highest_y = 0;
-    let mut num_valid_initial_velocities = 0;
+    let (_, (x_range, y_range)) = parse_input(input).unwrap();
+    let target = Target { x_range, y_range };
 
-    for initial_vy in y_min..-y_min {
-        for initial_vx in 0..=max(0, x_max) {
-            let mut y = 0;
-            let mut vy = initial_vy;
+    let launches = match target.launches() {
+        Ok(launches) => launches,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
 
-            let mut x = 0;
-            let mut vx = initial_vx;
+    let highest_y = launches.iter().map(|launch| launch.peak).max();
+    match highest_y {
+        Some(highest_y) => println!("Part 1: {}", highest_y),
+        None => println!("Part 1: the target cannot be hit"),
+    }
+    println!("Part 2: {}", launches.len());
 
-            let mut highest_y = 0;
+    if list {
+        for launch in &launches {
+            println!("{}", launch);
+        }
+    }
+
+    if let Some((vx, vy)) = launch_velocity {
+        let trajectory = target.trajectory(vx, vy);
+        print!("{}", target.render(&trajectory));
+        println!("{},{}: {}", vx, vy, trajectory.outcome);
+    }
+}
 
-            while x <= x_max && y >= y_min {
-                x += vx;
-                y += vy;
-                vx = max(0, vx - 1);
-                vy -= 1;
+#[derive(Debug, Copy, Clone)]
+struct Target {
+    x_range: (i64, i64),
+    y_range: (i64, i64),
+}
 
-                highest_y = max(highest_y, y);
+#[derive(Debug)]
+enum TargetError {
+    /// The probe can stop above or below the target, and there are infinitely many vertical
+    /// velocities that come back down through it
+    InfinitelyManyLaunches,
+}
 
-                if x_min <= x && x <= x_max && y_min <= y && y <= y_max {
-                    num_valid_initial_velocities += 1;
-                    overall_highest_y = max(overall_highest_y, highest_y);
-                    break;
-                }
+impl Display for TargetError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TargetError::InfinitelyManyLaunches => {
+                write!(f, "infinitely many initial velocities hit the target")
             }
         }
     }
+}
+
+/// An initial velocity that hits the target
+#[derive(Debug, Copy, Clone)]
+struct Launch {
+    vx: i64,
+    vy: i64,
+    /// The first step at which the probe is within the target
+    first_hit: i64,
+    /// The highest y position reached up to the first hit
+    peak: i64,
+}
+
+impl Display for Launch {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{},{} hits at step {}, peak {}", self.vx, self.vy, self.first_hit, self.peak)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Outcome {
+    /// The probe is within the target after the given step
+    Hit(usize),
+    /// The probe reached or went past the target horizontally, but missed it
+    Overshoot,
+    /// The probe never got as far as the target horizontally
+    Undershoot,
+}
+
+impl Display for Outcome {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Outcome::Hit(step) => write!(f, "hit at step {}", step),
+            Outcome::Overshoot => write!(f, "overshoot"),
+            Outcome::Undershoot => write!(f, "undershoot"),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Trajectory {
+    /// Position after each step, up to the hit or until the target can no longer be reached
+    positions: Vec<(i64, i64)>,
+    outcome: Outcome,
+}
+
+/// Inclusive range of steps, unbounded if `last` is `None`
+#[derive(Debug, Copy, Clone)]
+struct Steps {
+    first: i64,
+    last: Option<i64>,
+}
+
+impl Steps {
+    fn intersect(self, other: Steps) -> Option<Steps> {
+        let first = max(self.first, other.first);
+        let last = match (self.last, other.last) {
+            (Some(l1), Some(l2)) => Some(min(l1, l2)),
+            (last, None) | (None, last) => last,
+        };
+        match last {
+            Some(last) if last < first => None,
+            _ => Some(Steps { first, last }),
+        }
+    }
+}
 
-    println!("Part 1: {}", overall_highest_y);
-    println!("Part 2: {}", num_valid_initial_velocities);
+/// Position after `t` steps when starting at 0 with velocity `v`, if the velocity keeps
+/// decreasing by one every step
+fn position(v: i64, t: i64) -> i64 {
+    t * v - t * (t - 1) / 2
 }
 
+/// The (possibly empty) range of integer `t` for which `position(v, t) >= c`. This is the
+/// quadratic inequality `t^2 - (2v + 1)t + 2c <= 0`.
+fn steps_at_least(v: i64, c: i64) -> Option<(i64, i64)> {
+    let b = 2 * v + 1;
+    let discriminant = b * b - 8 * c;
+    if discriminant < 0 {
+        return None;
+    }
+
+    // Rounding the square root down does not change the rounded roots
+    let root = (discriminant as u64).isqrt() as i64;
+    let first = -(root - b).div_euclid(2);
+    let last = (b + root).div_euclid(2);
+    if first <= last { Some((first, last)) } else { None }
+}
+
+impl Target {
+    fn contains(&self, (x, y): (i64, i64)) -> bool {
+        let (x_min, x_max) = self.x_range;
+        let (y_min, y_max) = self.y_range;
+        x_min <= x && x <= x_max && y_min <= y && y <= y_max
+    }
+
+    /// Simulates the probe step by step until it hits the target or can no longer reach it
+    fn trajectory(&self, mut vx: i64, mut vy: i64) -> Trajectory {
+        let (x_min, x_max) = self.x_range;
+        let y_min = self.y_range.0;
+        let (mut x, mut y) = (0, 0);
+        let mut positions = vec![];
+        // Whether the probe has been within or on both sides of the target's columns
+        let mut reached_x_range = x_min <= 0 && 0 <= x_max;
+
+        loop {
+            let (previous_x, previous_y) = (x, y);
+            x += vx;
+            y += vy;
+            vx -= vx.signum();
+            vy -= 1;
+            positions.push((x, y));
+
+            if self.contains((x, y)) {
+                return Trajectory { outcome: Outcome::Hit(positions.len()), positions };
+            }
+
+            reached_x_range |= min(previous_x, x) <= x_max && max(previous_x, x) >= x_min;
+            let is_moving_away = (x > x_max && vx >= 0) || (x < x_min && vx <= 0);
+            let has_fallen_past = y < y_min && y < previous_y;
+            if is_moving_away || has_fallen_past {
+                let outcome = if reached_x_range { Outcome::Overshoot } else { Outcome::Undershoot };
+                return Trajectory { positions, outcome };
+            }
+        }
+    }
+
+    /// Draws the trajectory like the puzzle's diagrams, with `S` at the launcher, `#` at each
+    /// position of the probe and `T` for the rest of the target
+    fn render(&self, trajectory: &Trajectory) -> String {
+        let points = || trajectory.positions.iter()
+            .copied()
+            .chain([(0, 0), (self.x_range.0, self.y_range.0), (self.x_range.1, self.y_range.1)]);
+        let x_min = points().map(|(x, _)| x).min().unwrap();
+        let x_max = points().map(|(x, _)| x).max().unwrap();
+        let y_min = points().map(|(_, y)| y).min().unwrap();
+        let y_max = points().map(|(_, y)| y).max().unwrap();
+
+        let mut rendering = String::new();
+        for y in (y_min..=y_max).rev() {
+            for x in x_min..=x_max {
+                rendering.push(if (x, y) == (0, 0) {
+                    'S'
+                } else if trajectory.positions.contains(&(x, y)) {
+                    '#'
+                } else if self.contains((x, y)) {
+                    'T'
+                } else {
+                    '.'
+                });
+            }
+            rendering.push('\n');
+        }
+        rendering
+    }
+
+    /// Horizontal velocities worth trying, since any other velocity misses at the first step and
+    /// only moves further away
+    fn vx_bounds(&self) -> (i64, i64) {
+        let (x_min, x_max) = self.x_range;
+        (min(x_min, 0), max(x_max, 0))
+    }
+
+    /// Steps at which the probe is horizontally within the target
+    fn x_steps(&self, vx: i64) -> Option<Steps> {
+        // Mirror the target so the probe moves towards positive x
+        let (x_min, x_max) = match vx.signum() {
+            -1 => (-self.x_range.1, -self.x_range.0),
+            _ => self.x_range,
+        };
+        let vx = vx.abs();
+
+        if vx == 0 {
+            // The probe never moves
+            return (x_min <= 0 && 0 <= x_max).then_some(Steps { first: 1, last: None });
+        }
+
+        // Drag stops the probe at its furthest position, so once it is past a bound it stays
+        // past it
+        let (reached_min, _) = steps_at_least(vx, x_min)?;
+        let last = steps_at_least(vx, x_max + 1).map(|(past_max, _)| past_max - 1);
+        Steps { first: max(reached_min, 1), last: None }.intersect(Steps { first: 1, last })
+    }
+
+    /// Steps at which the probe is vertically within the target. The probe goes up and comes
+    /// back down, so it can pass through the target twice.
+    fn y_steps(&self, vy: i64) -> Vec<Steps> {
+        let (y_min, y_max) = self.y_range;
+        let above_min = match steps_at_least(vy, y_min) {
+            Some((first, last)) if last >= 1 => Steps { first: max(first, 1), last: Some(last) },
+            _ => return vec![],
+        };
+
+        match steps_at_least(vy, y_max + 1) {
+            Some((first, last)) => [
+                Steps { first: 1, last: Some(first - 1) },
+                Steps { first: last + 1, last: None },
+            ]
+                .into_iter()
+                .filter_map(|steps| steps.intersect(above_min))
+                .collect(),
+            None => vec![above_min],
+        }
+    }
+
+    /// Finds every initial velocity that is within the target after some step, by intersecting
+    /// the steps at which each axis is within the target
+    fn launches(&self) -> Result<Vec<Launch>, TargetError> {
+        let (vx_min, vx_max) = self.vx_bounds();
+        let x_steps: Vec<(i64, Steps)> = (vx_min..=vx_max)
+            .filter_map(|vx| self.x_steps(vx).map(|steps| (vx, steps)))
+            .collect();
+
+        let (y_min, y_max) = self.y_range;
+        let (vy_min, vy_max) = if y_max < 0 {
+            // Going up, the probe comes back to 0 with velocity -vy - 1
+            (y_min, -y_min - 1)
+        } else if y_min > 0 {
+            // The probe never goes up if vy <= 0, and going up faster overshoots the target at
+            // the first step and on the way back down
+            (1, y_max)
+        } else {
+            // The probe comes back to 0 at step 2vy + 1 for any vy >= 0, so it must have come
+            // to a stop horizontally outside the target before that
+            let last_step = x_steps.iter()
+                .map(|(_, steps)| steps.last.ok_or(TargetError::InfinitelyManyLaunches))
+                .try_fold(0, |last_step, last| last.map(|last| max(last_step, last)))?;
+            (y_min, max(y_max, last_step))
+        };
+
+        let mut launches = vec![];
+        for vy in vy_min..=vy_max {
+            let y_steps = self.y_steps(vy);
+            for &(vx, x_steps) in &x_steps {
+                let first_hit = y_steps.iter()
+                    .filter_map(|&steps| steps.intersect(x_steps))
+                    .map(|steps| steps.first)
+                    .min();
+
+                if let Some(first_hit) = first_hit {
+                    let peak = max(0, position(vy, min(first_hit, max(vy, 0))));
+                    launches.push(Launch { vx, vy, first_hit, peak });
+                }
+            }
+        }
+        Ok(launches)
+    }
+}
 
-fn parse_input(input: &str) -> IResult<&str, ((i32, i32), (i32, i32))> {
+fn parse_input(input: &str) -> IResult<&str, ((i64, i64), (i64, i64))> {
     preceded(
         tag("target area: "),
         separated_pair(
@@ -62,16 +343,16 @@ fn parse_input(input: &str) -> IResult<&str, ((i32, i32), (i32, i32))> {
     )(input)
 }
 
-fn parse_range(input: &str) -> IResult<&str, (i32, i32)> {
+fn parse_range(input: &str) -> IResult<&str, (i64, i64)> {
     separated_pair(parse_number, tag(".."), parse_number)(input)
 }
 
-fn parse_number(input: &str) -> IResult<&str, i32> {
+fn parse_number(input: &str) -> IResult<&str, i64> {
     map(
         recognize(tuple((
             opt(tag("-")),
             digit1
         ))),
-        |s: &str| s.parse::<i32>().unwrap(),
+        |s: &str| s.parse::<i64>().unwrap(),
     )(input)
 }
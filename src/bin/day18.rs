@@ -1,15 +1,16 @@
 use std::fmt::{Display, Formatter};
 use std::fs::File;
 use std::io::Read;
+use std::str::FromStr;
 use itertools::Itertools;
 
 use nom::branch::alt;
 use nom::bytes::complete::tag;
 use nom::character::complete::{digit1, newline};
-use nom::combinator::map;
+use nom::combinator::{all_consuming, cut, map};
 use nom::IResult;
 use nom::multi::separated_list1;
-use nom::sequence::{delimited, separated_pair};
+use nom::sequence::{preceded, separated_pair, terminated};
 
 fn main() {
     let mut file = File::open("input/day18.txt").unwrap();
@@ -17,25 +18,78 @@ fn main() {
     file.read_to_string(&mut input).unwrap();
     let input = input.as_str();
 
+    // Usage: day18 [--check] [--trace <number> [<number>]]
+    // --check also runs the tree implementation and checks that both give the same results.
+    // --trace reduces the number, or the sum of the two numbers, showing each step.
+    let mut check = false;
+    let mut trace = None;
+    let mut args = std::env::args().skip(1).peekable();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--check" => check = true,
+            "--trace" => {
+                let n1: Number = args.next().unwrap().parse().unwrap();
+                let n2: Option<Number> = args.next_if(|arg| !arg.starts_with("--"))
+                    .map(|arg| arg.parse().unwrap());
+                trace = Some((n1, n2));
+            }
+            _ => panic!("unknown argument: {}", arg),
+        }
+    }
+
     let (_, numbers) = parse_input(input).unwrap();
+    let flat_numbers: Vec<FlatNumber> = numbers.iter().map(FlatNumber::from).collect();
 
-    let result_part1 = numbers.iter()
+    let sum = flat_numbers.iter()
         .cloned()
-        .reduce(|n1, n2| reduce(add(n1, n2)))
+        .reduce(|n1, n2| n1.add(&n2))
         .unwrap();
 
-    println!("Part 1: {}", magnitude(result_part1));
+    println!("Part 1: {}", sum.magnitude());
 
-    let result_part2 = numbers.iter()
-        .cloned()
+    let result_part2 = flat_numbers.iter()
         .tuple_combinations::<(_, _)>()
-        .map(|(n1, n2)| {
-            magnitude(reduce(add(n1, n2)))
-        })
+        .map(|(n1, n2)| n1.add(n2).magnitude())
         .max()
         .unwrap();
 
     println!("Part 2: {}", result_part2);
+
+    if check {
+        let tree_sum = numbers.iter()
+            .cloned()
+            .reduce(|n1, n2| reduce(add(n1, n2)))
+            .unwrap();
+        let is_same_sum = sum.to_number() == tree_sum && sum.magnitude() == magnitude(tree_sum);
+        println!("Sum: {}", if is_same_sum { "ok" } else { "mismatch" });
+
+        let mismatches = numbers.iter()
+            .zip(&flat_numbers)
+            .tuple_combinations::<(_, _)>()
+            .filter(|&((n1, f1), (n2, f2))| {
+                f1.add(f2).to_number() != reduce(add(n1.clone(), n2.clone()))
+            })
+            .count();
+        println!("Pairwise sums: {} mismatches", mismatches);
+
+        let is_round_trip = input.lines()
+            .all(|line| line.parse::<Number>().is_ok_and(|n| n.to_string() == line));
+        println!("Round trip: {}", if is_round_trip { "ok" } else { "mismatch" });
+    }
+
+    if let Some((n1, n2)) = trace {
+        let n = match n2 {
+            Some(n2) => {
+                let sum = add(n1, n2);
+                println!("after addition: {}", sum);
+                sum
+            }
+            None => n1,
+        };
+        for step in reduce_traced(n) {
+            println!("{}", step);
+        }
+    }
 }
 
 fn magnitude(n: Number) -> usize {
@@ -84,14 +138,13 @@ fn reduce_explode(n: &Number) -> Option<Number> {
 
     fn explode(n: &Number, depth: usize) -> Option<(usize, Number, usize)> {
         match n {
-            Number::Pair(n1, n2) if depth == 4 => {
-                match (n1.as_ref(), n2.as_ref()) {
-                    (Number::Regular(nl), Number::Regular(nr)) => Some((*nl, Number::Regular(0), *nr)),
-                    // At depth 4, only regular numbers should remain
-                    _ => unreachable!(),
-                }
-            }
             Number::Pair(n1, n2) => {
+                // Numbers that are not reduced yet can have pairs nested deeper than 4
+                if depth >= 4 {
+                    if let (Number::Regular(nl), Number::Regular(nr)) = (n1.as_ref(), n2.as_ref()) {
+                        return Some((*nl, Number::Regular(0), *nr));
+                    }
+                }
                 // Explode left pair
                 if let Some((nl, n1, nr)) = explode(n1.as_ref(), depth + 1) {
                     return Some((nl, add(n1, add_left(*n2.clone(), nr)), 0));
@@ -132,7 +185,8 @@ fn reduce_split(n: &Number) -> Option<Number> {
     }
 }
 
-#[derive(Debug, Clone)]
+/// A snailfish number as a tree. This is the reference implementation for `FlatNumber`.
+#[derive(Debug, Clone, PartialEq)]
 enum Number {
     Regular(usize),
     Pair(Box<Number>, Box<Number>),
@@ -147,6 +201,213 @@ impl Display for Number {
     }
 }
 
+#[derive(Debug, Copy, Clone)]
+enum Action {
+    /// A pair of regular numbers exploded
+    Explode(usize, usize),
+    /// A regular number was split
+    Split(usize),
+}
+
+/// A single action while reducing a number, and the number after it
+#[derive(Debug)]
+struct ReductionStep {
+    action: Action,
+    result: Number,
+}
+
+impl Display for Action {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Action::Explode(left, right) => write!(f, "explode [{},{}]", left, right),
+            Action::Split(n) => write!(f, "split {}", n),
+        }
+    }
+}
+
+/// Formatted like the puzzle's worked example, like `after explode:  [[1,2],3]`. The alternate
+/// flag (`{:#}`) also shows which pair exploded or which number was split.
+impl Display for ReductionStep {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.action {
+            Action::Explode(..) => write!(f, "after explode:  {}", self.result)?,
+            Action::Split(_) => write!(f, "after split:    {}", self.result)?,
+        }
+        if f.alternate() {
+            write!(f, " ({})", self.action)?;
+        }
+        Ok(())
+    }
+}
+
+/// Reduces the number, yielding every action along the way
+fn reduce_traced(n: Number) -> impl Iterator<Item = ReductionStep> {
+    let mut flat = FlatNumber::from(&n);
+    std::iter::from_fn(move || {
+        let action = flat.explode()
+            .map(|(left, right)| Action::Explode(left, right))
+            .or_else(|| flat.split().map(Action::Split))?;
+        Some(ReductionStep { action, result: flat.to_number() })
+    })
+}
+
+/// A regular number in a `FlatNumber`
+#[derive(Debug, Copy, Clone)]
+struct Entry {
+    value: usize,
+    /// Number of pairs the regular number is nested inside
+    depth: usize,
+}
+
+/// A snailfish number as its regular numbers from left to right. Two adjacent entries at the
+/// same depth that are not part of a deeper pair form a pair, so the tree can be rebuilt.
+#[derive(Debug, Clone)]
+struct FlatNumber {
+    entries: Vec<Entry>,
+}
+
+impl From<&Number> for FlatNumber {
+    fn from(n: &Number) -> Self {
+        fn flatten(n: &Number, depth: usize, entries: &mut Vec<Entry>) {
+            match n {
+                Number::Regular(value) => entries.push(Entry { value: *value, depth }),
+                Number::Pair(n1, n2) => {
+                    flatten(n1, depth + 1, entries);
+                    flatten(n2, depth + 1, entries);
+                }
+            }
+        }
+
+        let mut entries = vec![];
+        flatten(n, 0, &mut entries);
+        FlatNumber { entries }
+    }
+}
+
+impl FlatNumber {
+    /// Adds and reduces the two numbers
+    fn add(&self, other: &FlatNumber) -> FlatNumber {
+        let entries = self.entries.iter()
+            .chain(&other.entries)
+            .map(|&Entry { value, depth }| Entry { value, depth: depth + 1 })
+            .collect();
+
+        let mut n = FlatNumber { entries };
+        n.reduce();
+        n
+    }
+
+    fn reduce(&mut self) {
+        while self.explode().is_some() || self.split().is_some() {}
+    }
+
+    /// Finds the leftmost pair of two regular numbers nested inside four pairs, returning the
+    /// index of its left number
+    fn find_exploding_pair(&self) -> Option<usize> {
+        // Depths of the values whose pair is not complete yet, with the index of regular numbers.
+        // Once a number is reduced the first entry deeper than 4 starts such a pair, but numbers
+        // that are not reduced yet can nest pairs deeper.
+        let mut stack: Vec<(usize, Option<usize>)> = vec![];
+        for (i, entry) in self.entries.iter().enumerate() {
+            let mut value = (entry.depth, Some(i));
+            while let Some(&(_, left_index)) = stack.last().filter(|(d, _)| *d == value.0) {
+                stack.pop();
+                if let (Some(left_index), Some(_)) = (left_index, value.1) {
+                    if value.0 > 4 {
+                        return Some(left_index);
+                    }
+                }
+                value = (value.0 - 1, None);
+            }
+            stack.push(value);
+        }
+        None
+    }
+
+    /// Explodes the leftmost pair of regular numbers nested inside four pairs, if any, returning
+    /// its values
+    fn explode(&mut self) -> Option<(usize, usize)> {
+        let i = self.find_exploding_pair()?;
+        let Entry { value: left, depth } = self.entries[i];
+        let right = self.entries.remove(i + 1);
+
+        if i > 0 {
+            self.entries[i - 1].value += left;
+        }
+        if let Some(entry) = self.entries.get_mut(i + 1) {
+            entry.value += right.value;
+        }
+        self.entries[i] = Entry { value: 0, depth: depth - 1 };
+        Some((left, right.value))
+    }
+
+    /// Splits the leftmost regular number of 10 or more, if any, returning its value
+    fn split(&mut self) -> Option<usize> {
+        let i = self.entries.iter().position(|entry| entry.value >= 10)?;
+        let Entry { value, depth } = self.entries[i];
+
+        self.entries[i] = Entry { value: value / 2, depth: depth + 1 };
+        self.entries.insert(i + 1, Entry { value: value - value / 2, depth: depth + 1 });
+        Some(value)
+    }
+
+    fn magnitude(&self) -> usize {
+        // Combine pairs as soon as both of their halves are known
+        let mut stack: Vec<Entry> = vec![];
+        for &entry in &self.entries {
+            let mut entry = entry;
+            while let Some(&left) = stack.last().filter(|left| left.depth == entry.depth) {
+                stack.pop();
+                entry = Entry { value: 3 * left.value + 2 * entry.value, depth: entry.depth - 1 };
+            }
+            stack.push(entry);
+        }
+        stack[0].value
+    }
+
+    /// Rebuilds the number as a tree
+    fn to_number(&self) -> Number {
+        let mut stack: Vec<(Number, usize)> = vec![];
+        for &Entry { value, depth } in &self.entries {
+            let mut n = (Number::Regular(value), depth);
+            while let Some((_, left_depth)) = stack.last().filter(|(_, d)| *d == n.1) {
+                let depth = left_depth - 1;
+                let (left, _) = stack.pop().unwrap();
+                n = (add(left, n.0), depth);
+            }
+            stack.push(n);
+        }
+        stack.pop().unwrap().0
+    }
+}
+
+#[derive(Debug)]
+struct ParseNumberError {
+    /// Offset in bytes of the first character that could not be parsed
+    offset: usize,
+}
+
+impl Display for ParseNumberError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid snailfish number at offset {}", self.offset)
+    }
+}
+
+impl FromStr for Number {
+    type Err = ParseNumberError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        all_consuming(parse_snailfish_number)(s)
+            .map(|(_, n)| n)
+            .map_err(|e| match e {
+                nom::Err::Error(e) | nom::Err::Failure(e) => {
+                    ParseNumberError { offset: s.len() - e.input.len() }
+                }
+                nom::Err::Incomplete(_) => unreachable!(),
+            })
+    }
+}
+
 fn parse_input(input: &str) -> IResult<&str, Vec<Number>> {
     separated_list1(
         newline,
@@ -157,15 +418,15 @@ fn parse_input(input: &str) -> IResult<&str, Vec<Number>> {
 fn parse_snailfish_number(input: &str) -> IResult<&str, Number> {
     alt((
         map(
-            delimited(
-                tag("["),
+            // Once a pair is opened it must be complete, so errors point inside it
+            preceded(tag("["), cut(terminated(
                 separated_pair(
                     parse_snailfish_number,
                     tag(","),
                     parse_snailfish_number,
                 ),
                 tag("]"),
-            ),
+            ))),
             |(n1, n2)| add(n1, n2),
         ),
         map(parse_number, |n| Number::Regular(n))
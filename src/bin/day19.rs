@@ -0,0 +1,100 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use aoc_2021::days::day19::{
+    align_reports, input_generator, parse_setting, AlignConfig, ConfigError, Report, Scans,
+};
+
+fn main() {
+    // Day 19 is solved by the runner too, so share its input
+    let mut file = File::open("input/2021/day19.txt").unwrap();
+    let mut input = String::new();
+    file.read_to_string(&mut input).unwrap();
+    let input = input.as_str();
+
+    // Usage: day19 [--min-overlap <n>] [--jitter <n>] [--outlier-fraction <f>]
+    //              [--reference <scanner>] [--export <path>]
+    // Exports the scanner poses and the merged beacons in the frame of the reference scanner
+    // (default 0), as PLY if the path ends in .ply and as CSV otherwise. The alignment settings
+    // default to the runner's, which can be set with DAY19_* environment variables.
+    let mut reference = 0;
+    let mut export_path = None;
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let config = parse_args(args, &mut reference, &mut export_path)
+        .and_then(AlignConfig::validate);
+    let config = match config {
+        Ok(config) => config,
+        Err(e) => {
+            println!("Invalid settings: {}", e);
+            return;
+        }
+    };
+
+    match input_generator(input.trim_end()) {
+        Scans::Planar(reports) => run(&reports, &config, reference, export_path.as_deref()),
+        Scans::Spatial(reports) => run(&reports, &config, reference, export_path.as_deref()),
+    }
+}
+
+/// Applies the command line arguments on top of the settings from the environment
+fn parse_args(
+    args: Vec<String>,
+    reference: &mut usize,
+    export_path: &mut Option<PathBuf>,
+) -> Result<AlignConfig, ConfigError> {
+    let mut config = AlignConfig::from_env()?;
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--min-overlap" => {
+                config.min_overlap = parse_setting("--min-overlap", args.next().unwrap())?;
+            }
+            "--jitter" => config.jitter = parse_setting("--jitter", args.next().unwrap())?,
+            "--outlier-fraction" => {
+                let value = args.next().unwrap();
+                config.outlier_fraction = parse_setting("--outlier-fraction", value)?;
+            }
+            "--reference" => *reference = args.next().unwrap().parse().unwrap(),
+            "--export" => *export_path = args.next().map(PathBuf::from),
+            _ => panic!("unknown argument: {}", arg),
+        }
+    }
+    Ok(config)
+}
+
+fn run<const D: usize>(
+    reports: &[Report<D>],
+    config: &AlignConfig,
+    reference: usize,
+    export_path: Option<&Path>,
+) {
+    let alignment = align_reports(reports, config);
+
+    let disconnected = alignment.disconnected();
+    if disconnected.is_empty() {
+        println!("Part 1: {}", alignment.beacons.len());
+        println!("Part 2: {}", alignment.scanner_range());
+    } else {
+        println!("Scanners {:?} cannot be aligned with scanner 0", disconnected);
+    }
+
+    if let Some(path) = export_path {
+        // Export even if some reports could not be aligned, to help find out why
+        let exported = match alignment.relative_to(reference) {
+            Some(exported) => exported,
+            None => {
+                println!("Export failed: scanner {} is not aligned", reference);
+                return;
+            }
+        };
+
+        let contents = match path.extension().and_then(|extension| extension.to_str()) {
+            Some("ply") => exported.to_ply(),
+            _ => exported.to_csv(),
+        };
+        if let Err(e) = std::fs::write(path, contents) {
+            println!("Export failed: {}", e);
+        }
+    }
+}
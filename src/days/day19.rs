@@ -1,62 +1,101 @@
+use std::error::Error;
+use std::fmt::{Debug, Display, Formatter, Write};
 use std::str::FromStr;
 
 use hashbrown::{HashMap, HashSet};
-use itertools::Itertools;
-use nalgebra::{Matrix3, Vector3};
+use itertools::{iproduct, Itertools};
+use nalgebra::{SMatrix, SVector};
 use nom::bytes::complete::tag;
 use nom::character::complete::{digit1, newline};
-use nom::combinator::{map, map_res, opt, recognize};
+use nom::combinator::{map_res, opt, recognize};
 use nom::IResult;
 use nom::multi::{count, separated_list1};
 use nom::sequence::{delimited, terminated, tuple};
 
-static ROTATION_MATRICES: [Matrix3<i32>; 24] = [
-    Matrix3::new(1, 0, 0, 0, 1, 0, 0, 0, 1),
-    Matrix3::new(1, 0, 0, 0, 0, 1, 0, -1, 0),
-    Matrix3::new(1, 0, 0, 0, -1, 0, 0, 0, -1),
-    Matrix3::new(1, 0, 0, 0, 0, -1, 0, 1, 0),
-    Matrix3::new(0, 1, 0, 0, 0, 1, 1, 0, 0),
-    Matrix3::new(0, 1, 0, 1, 0, 0, 0, 0, -1),
-    Matrix3::new(0, 1, 0, 0, 0, -1, -1, 0, 0),
-    Matrix3::new(0, 1, 0, -1, 0, 0, 0, 0, 1),
-    Matrix3::new(0, 0, 1, 1, 0, 0, 0, 1, 0),
-    Matrix3::new(0, 0, 1, 0, 1, 0, -1, 0, 0),
-    Matrix3::new(0, 0, 1, -1, 0, 0, 0, -1, 0),
-    Matrix3::new(0, 0, 1, 0, -1, 0, 1, 0, 0),
-    Matrix3::new(-1, 0, 0, 0, -1, 0, 0, 0, 1),
-    Matrix3::new(-1, 0, 0, 0, 0, 1, 0, 1, 0),
-    Matrix3::new(-1, 0, 0, 0, 1, 0, 0, 0, -1),
-    Matrix3::new(-1, 0, 0, 0, 0, -1, 0, -1, 0),
-    Matrix3::new(0, -1, 0, 0, 0, -1, 1, 0, 0),
-    Matrix3::new(0, -1, 0, 1, 0, 0, 0, 0, 1),
-    Matrix3::new(0, -1, 0, 0, 0, 1, -1, 0, 0),
-    Matrix3::new(0, -1, 0, -1, 0, 0, 0, 0, -1),
-    Matrix3::new(0, 0, -1, -1, 0, 0, 0, 1, 0),
-    Matrix3::new(0, 0, -1, 0, 1, 0, 1, 0, 0),
-    Matrix3::new(0, 0, -1, 1, 0, 0, 0, -1, 0),
-    Matrix3::new(0, 0, -1, 0, -1, 0, -1, 0, 0),
-];
-
-fn norm_l1(v: &Vector3<i32>) -> i32 {
-    v[0].abs() + v[1].abs() + v[2].abs()
-}
-
-fn norm_lmax(v: &Vector3<i32>) -> i32 {
-    v[0].abs()
-        .max(v[1].abs())
-        .max(v[2].abs())
+pub type Point<const D: usize> = SVector<i32, D>;
+pub type Rotation<const D: usize> = SMatrix<i32, D, D>;
+pub type Report<const D: usize> = Vec<Point<D>>;
+
+/// Scanner reports, in 2D or 3D depending on the number of coordinates per beacon
+#[derive(Debug)]
+pub enum Scans {
+    Planar(Vec<Report<2>>),
+    Spatial(Vec<Report<3>>),
+}
+
+/// The orientations a scanner can have, as signed permutation matrices. A 3D scanner can face
+/// along any axis with any of four directions up. A 2D scanner can also be turned over, which
+/// mirrors its scan.
+fn orientations<const D: usize>() -> Vec<Rotation<D>> {
+    let all_signs = (0..D).map(|_| [1, -1].into_iter()).multi_cartesian_product();
+    (0..D).permutations(D)
+        .cartesian_product(all_signs)
+        .filter(|(permutation, signs)| {
+            let num_inversions = permutation.iter()
+                .tuple_combinations()
+                .filter(|(a, b)| a > b)
+                .count();
+            let parity = if num_inversions % 2 == 0 { 1 } else { -1 };
+            D == 2 || parity * signs.iter().product::<i32>() == 1
+        })
+        .map(|(permutation, signs)| {
+            Rotation::<D>::from_fn(|row, column| {
+                if permutation[row] == column { signs[row] } else { 0 }
+            })
+        })
+        .collect()
+}
+
+/// Maps points from a scanner's report into a reference frame
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Pose<const D: usize> {
+    pub rotation: Rotation<D>,
+    /// Position of the scanner in the reference frame
+    pub translation: Point<D>,
+}
+
+impl<const D: usize> Pose<D> {
+    fn identity() -> Self {
+        Pose { rotation: Rotation::identity(), translation: Point::zeros() }
+    }
+
+    fn apply(&self, p: &Point<D>) -> Point<D> {
+        self.rotation * p + self.translation
+    }
+
+    /// The pose that applies `inner` first and then `self`
+    fn compose(&self, inner: &Self) -> Self {
+        Pose {
+            rotation: self.rotation * inner.rotation,
+            translation: self.apply(&inner.translation),
+        }
+    }
+
+    fn inverse(&self) -> Self {
+        // Orientation matrices are orthogonal, so the inverse is the transpose
+        let m = self.rotation.transpose();
+        Pose { rotation: m, translation: -(m * self.translation) }
+    }
+}
+
+fn norm_l1<const D: usize>(v: &Point<D>) -> i32 {
+    v.iter().map(|c| c.abs()).sum()
+}
+
+fn norm_lmax<const D: usize>(v: &Point<D>) -> i32 {
+    v.iter().map(|c| c.abs()).max().unwrap_or(0)
 }
 
 #[aoc_generator(day19)]
-pub fn input_generator(input: &str) -> Vec<Vec<Vector3<i32>>> {
-    fn parse_input(input: &str) -> IResult<&str, Vec<Vec<Vector3<i32>>>> {
+pub fn input_generator(input: &str) -> Scans {
+    fn parse_input(input: &str) -> IResult<&str, Vec<Vec<Vec<i32>>>> {
         separated_list1(
             count(newline, 2),
             parse_report,
         )(input)
     }
 
-    fn parse_report(input: &str) -> IResult<&str, Vec<Vector3<i32>>> {
+    fn parse_report(input: &str) -> IResult<&str, Vec<Vec<i32>>> {
         // Parse header
         let (input, _) = terminated(
             delimited(tag("--- scanner "), digit1, tag(" ---")),
@@ -66,15 +105,8 @@ pub fn input_generator(input: &str) -> Vec<Vec<Vector3<i32>>> {
         separated_list1(newline, parse_coords)(input)
     }
 
-    fn parse_coords(input: &str) -> IResult<&str, Vector3<i32>> {
-        map(
-            tuple((
-                terminated(parse_signed_int, tag(",")),
-                terminated(parse_signed_int, tag(",")),
-                parse_signed_int,
-            )),
-            |(x, y, z)| [x, y, z].into(),
-        )(input)
+    fn parse_coords(input: &str) -> IResult<&str, Vec<i32>> {
+        separated_list1(tag(","), parse_signed_int)(input)
     }
 
     fn parse_signed_int<T: FromStr>(input: &str) -> IResult<&str, T> {
@@ -84,19 +116,46 @@ pub fn input_generator(input: &str) -> Vec<Vec<Vector3<i32>>> {
         )(input)
     }
 
-    parse_input(input).unwrap().1
+    fn to_reports<const D: usize>(reports: Vec<Vec<Vec<i32>>>) -> Vec<Report<D>> {
+        reports.into_iter()
+            .map(|report| {
+                report.into_iter()
+                    .map(|coords| {
+                        assert_eq!(coords.len(), D, "beacons have different dimensions");
+                        Point::from_column_slice(&coords)
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    let reports = parse_input(input).unwrap().1;
+    match reports[0][0].len() {
+        2 => Scans::Planar(to_reports(reports)),
+        3 => Scans::Spatial(to_reports(reports)),
+        dimensions => panic!("unsupported number of dimensions: {}", dimensions),
+    }
 }
 
 type Fingerprint = (i32, i32);
 
-fn fingerprint(p1: &Vector3<i32>, p2: &Vector3<i32>) -> Fingerprint {
+fn fingerprint<const D: usize>(p1: &Point<D>, p2: &Point<D>) -> Fingerprint {
     let d = p1 - p2;
     (norm_l1(&d), norm_lmax(&d))
 }
 
-fn extend_fingerprints_from_report(
-    fingerprints: &mut HashMap<Fingerprint, Vec<[Vector3<i32>; 2]>>,
-    report: &Vec<Vector3<i32>>,
+fn report_fingerprints<const D: usize>(report: &Report<D>) -> Vec<(Fingerprint, [&Point<D>; 2])> {
+    report.iter()
+        .tuple_combinations::<(_, _)>()
+        .map(|(p1, p2)| {
+            (fingerprint(p1, p2), [p1, p2])
+        })
+        .collect()
+}
+
+fn extend_fingerprints_from_report<const D: usize>(
+    fingerprints: &mut HashMap<Fingerprint, Vec<[Point<D>; 2]>>,
+    report: &Report<D>,
 ) {
     for (p1, p2) in report.iter().tuple_combinations::<(_, _)>() {
         let f = fingerprint(p1, p2);
@@ -104,20 +163,301 @@ fn extend_fingerprints_from_report(
     }
 }
 
+/// Settings for aligning scanner reports with each other
+#[derive(Debug, Clone)]
+pub struct AlignConfig {
+    /// Number of beacons two reports must have in common to be considered overlapping
+    pub min_overlap: usize,
+    /// How far each coordinate of a beacon can be from its true position in a report. With
+    /// jitter, beacons match if they are close enough rather than at exactly the same position.
+    pub jitter: i32,
+    /// Fraction of the overlapping beacons that may be missing from either report, or be
+    /// spurious points, while still considering the reports to overlap
+    pub outlier_fraction: f64,
+}
+
+impl Default for AlignConfig {
+    fn default() -> Self {
+        AlignConfig {
+            min_overlap: 12,
+            jitter: 0,
+            outlier_fraction: 0.0,
+        }
+    }
+}
+
+/// A setting has an invalid value
+#[derive(Debug)]
+pub struct ConfigError {
+    /// The setting, or the environment variable or command line flag it was given with
+    pub name: &'static str,
+    pub value: String,
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid value {:?} for {}", self.value, self.name)
+    }
+}
+
+impl Error for ConfigError {}
+
+/// Parses the value of a setting given as text, like an environment variable or a command line
+/// argument. The value is not checked, see `AlignConfig::validate`.
+pub fn parse_setting<T: FromStr>(name: &'static str, value: String) -> Result<T, ConfigError> {
+    value.parse().map_err(|_| ConfigError { name, value })
+}
+
+impl AlignConfig {
+    /// The runner does not take arguments, so settings can be overridden with the environment
+    /// variables `DAY19_MIN_OVERLAP`, `DAY19_JITTER` and `DAY19_OUTLIER_FRACTION`
+    pub fn from_env() -> Result<AlignConfig, ConfigError> {
+        fn var<T: FromStr>(name: &'static str, default: T) -> Result<T, ConfigError> {
+            std::env::var(name).map_or(Ok(default), |value| parse_setting(name, value))
+        }
+
+        let default = AlignConfig::default();
+        AlignConfig {
+            min_overlap: var("DAY19_MIN_OVERLAP", default.min_overlap)?,
+            jitter: var("DAY19_JITTER", default.jitter)?,
+            outlier_fraction: var("DAY19_OUTLIER_FRACTION", default.outlier_fraction)?,
+        }
+            .validate()
+    }
+
+    /// Checks that the settings are within their ranges
+    pub fn validate(self) -> Result<AlignConfig, ConfigError> {
+        let error = |name, value: &dyn Display| ConfigError { name, value: value.to_string() };
+        if self.min_overlap < 1 {
+            return Err(error("min_overlap", &self.min_overlap));
+        }
+        if self.jitter < 0 {
+            return Err(error("jitter", &self.jitter));
+        }
+        if !(0.0..1.0).contains(&self.outlier_fraction) {
+            return Err(error("outlier_fraction", &self.outlier_fraction));
+        }
+        Ok(self)
+    }
+
+    /// Whether beacons are seen at exactly their true positions, so a pose either matches the
+    /// overlapping beacons exactly or is wrong
+    fn is_exact(&self) -> bool {
+        self.jitter == 0 && self.outlier_fraction == 0.0
+    }
+
+    /// Number of beacons that must match for two reports to be considered overlapping
+    fn min_inliers(&self) -> usize {
+        (self.min_overlap as f64 * (1.0 - self.outlier_fraction)).ceil() as usize
+    }
+
+    /// Fingerprints that could belong to the same pair of beacons as `f`, given the jitter
+    fn nearby_fingerprints(
+        &self,
+        f: Fingerprint,
+        dimensions: usize,
+    ) -> impl Iterator<Item = Fingerprint> {
+        // Each coordinate of the difference between two beacons can be off by twice the jitter
+        let (l1, lmax) = f;
+        let (l1_error, lmax_error) = (2 * self.jitter * dimensions as i32, 2 * self.jitter);
+        (l1 - l1_error..=l1 + l1_error).cartesian_product(lmax - lmax_error..=lmax + lmax_error)
+    }
+}
+
+/// Finds a known beacon within `radius` of `p` in each coordinate
+fn find_nearby<const D: usize>(
+    known_beacons: &HashSet<Point<D>>,
+    p: &Point<D>,
+    radius: i32,
+) -> Option<Point<D>> {
+    if radius == 0 {
+        return known_beacons.get(p).copied();
+    }
+    (0..D).map(|_| -radius..=radius)
+        .multi_cartesian_product()
+        .map(|offset| p + Point::from_column_slice(&offset))
+        .find(|q| known_beacons.contains(q))
+}
+
+/// Some reports could not be aligned with the first one. The reports that could be aligned are
+/// still merged.
+#[derive(Debug)]
+pub struct DisconnectedError<const D: usize> {
+    /// Indices of the scanners whose reports could not be aligned
+    pub disconnected: Vec<usize>,
+    /// Groups of scanners whose reports overlap, directly or through other reports
+    pub components: Vec<Vec<usize>>,
+    /// Number of beacons seen by the aligned scanners
+    pub num_beacons: usize,
+    /// Pose of each scanner relative to scanner 0, if its report could be aligned
+    pub scanners: Vec<Option<Pose<D>>>,
+}
+
+impl<const D: usize> Display for DisconnectedError<D> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "scanners {:?} cannot be aligned with scanner 0, the overlapping groups are {:?}",
+            self.disconnected, self.components,
+        )?;
+        write!(f, "; the aligned scanners see {} beacons", self.num_beacons)?;
+        for (index, pose) in self.scanners.iter().enumerate() {
+            if let Some(pose) = pose {
+                write!(f, ", scanner {} is at {:?}", index, pose.translation.as_slice())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<const D: usize> Error for DisconnectedError<D> {}
+
 #[aoc(day19, part1)]
-pub fn solve_part1(input: &Vec<Vec<Vector3<i32>>>) -> usize {
-    solve_both(input).0
+pub fn solve_part1(input: &Scans) -> Result<usize, Box<dyn Error>> {
+    let config = AlignConfig::from_env()?;
+    let result = match input {
+        Scans::Planar(reports) => solve_both(reports, &config),
+        Scans::Spatial(reports) => solve_both(reports, &config),
+    };
+    result.map(|(num_beacons, _)| num_beacons)
 }
 
 #[aoc(day19, part2)]
-pub fn solve_part2(input: &Vec<Vec<Vector3<i32>>>) -> i32 {
-    solve_both(input).1
+pub fn solve_part2(input: &Scans) -> Result<i32, Box<dyn Error>> {
+    let config = AlignConfig::from_env()?;
+    let result = match input {
+        Scans::Planar(reports) => solve_both(reports, &config),
+        Scans::Spatial(reports) => solve_both(reports, &config),
+    };
+    result.map(|(_, scanner_range)| scanner_range)
+}
+
+fn solve_both<const D: usize>(
+    input: &[Report<D>],
+    config: &AlignConfig,
+) -> Result<(usize, i32), Box<dyn Error>> {
+    let alignment = align_reports(input, config);
+
+    let disconnected = alignment.disconnected();
+    if !disconnected.is_empty() {
+        return Err(Box::new(DisconnectedError {
+            disconnected,
+            components: overlap_components(input, config),
+            num_beacons: alignment.beacons.len(),
+            scanners: alignment.scanners,
+        }));
+    }
+
+    Ok((
+        // Part 1
+        alignment.beacons.len(),
+        // Part 2
+        alignment.scanner_range(),
+    ))
+}
+
+/// The reports that could be aligned with the first one
+#[derive(Debug)]
+pub struct Alignment<const D: usize> {
+    /// Beacons seen by the aligned scanners, relative to scanner 0
+    pub beacons: HashSet<Point<D>>,
+    /// Pose of each scanner relative to scanner 0, if its report could be aligned
+    pub scanners: Vec<Option<Pose<D>>>,
 }
 
-fn solve_both(input: &Vec<Vec<Vector3<i32>>>) -> (usize, i32) {
+impl<const D: usize> Alignment<D> {
+    pub fn disconnected(&self) -> Vec<usize> {
+        self.scanners.iter()
+            .positions(|scanner| scanner.is_none())
+            .collect()
+    }
+
+    /// Largest Manhattan distance between two aligned scanners
+    pub fn scanner_range(&self) -> i32 {
+        self.scanners.iter()
+            .flatten()
+            .map(|pose| pose.translation)
+            .tuple_combinations::<(_, _)>()
+            .map(|(s1, s2)| norm_l1(&(s1 - s2)))
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Expresses the alignment in the frame of another aligned scanner
+    pub fn relative_to(&self, reference: usize) -> Option<Self> {
+        let to_reference = self.scanners.get(reference).copied().flatten()?.inverse();
+        Some(Alignment {
+            beacons: self.beacons.iter().map(|p| to_reference.apply(p)).collect(),
+            scanners: self.scanners.iter()
+                .map(|pose| pose.map(|pose| to_reference.compose(&pose)))
+                .collect(),
+        })
+    }
+
+    /// All beacons in a fixed order
+    pub fn merged_beacons(&self) -> Vec<Point<D>> {
+        let mut beacons = self.beacons.iter().copied().collect::<Vec<_>>();
+        beacons.sort_unstable_by(|p1, p2| p1.as_slice().cmp(p2.as_slice()));
+        beacons
+    }
+
+    /// One row per aligned scanner with its position and rotation matrix (row by row), followed
+    /// by one row per beacon
+    pub fn to_csv(&self) -> String {
+        let axes = ["x", "y", "z"];
+        let header = axes[..D].iter()
+            .map(|axis| axis.to_string())
+            .chain(iproduct!(1..=D, 1..=D).map(|(row, column)| format!("r{}{}", row, column)))
+            .join(",");
+        let mut csv = format!("kind,index,{}\n", header);
+
+        for (index, pose) in self.scanners.iter().enumerate() {
+            if let Some(pose) = pose {
+                let t = pose.translation.iter().join(",");
+                // Matrices are stored column by column
+                let rows = pose.rotation.transpose().iter().join(",");
+                writeln!(csv, "scanner,{},{},{}", index, t, rows).unwrap();
+            }
+        }
+        for (index, p) in self.merged_beacons().iter().enumerate() {
+            let no_rotation = ",".repeat(D * D);
+            writeln!(csv, "beacon,{},{}{}", index, p.iter().join(","), no_rotation).unwrap();
+        }
+        csv
+    }
+
+    /// An ASCII PLY point cloud with the scanners in red and the beacons in white. 2D points are
+    /// placed at z = 0.
+    pub fn to_ply(&self) -> String {
+        let scanners = self.scanners.iter().flatten().map(|pose| (pose.translation, (255, 0, 0)));
+        let beacons = self.merged_beacons().into_iter().map(|p| (p, (255, 255, 255)));
+        let vertices = scanners.chain(beacons).collect::<Vec<_>>();
+
+        let mut ply = String::from("ply\nformat ascii 1.0\n");
+        writeln!(ply, "element vertex {}", vertices.len()).unwrap();
+        for property in ["float x", "float y", "float z"] {
+            writeln!(ply, "property {}", property).unwrap();
+        }
+        for property in ["uchar red", "uchar green", "uchar blue"] {
+            writeln!(ply, "property {}", property).unwrap();
+        }
+        ply.push_str("end_header\n");
+        for (p, (r, g, b)) in vertices {
+            let coords = (0..3).map(|axis| p.get(axis).copied().unwrap_or(0)).join(" ");
+            writeln!(ply, "{} {} {} {}", coords, r, g, b).unwrap();
+        }
+        ply
+    }
+}
+
+/// Aligns reports with the first one until none of the remaining reports overlap the beacons
+/// found so far. The poses and beacons are given in the frame of the first scanner, and reports
+/// that could not be aligned are left out.
+pub fn align_reports<const D: usize>(input: &[Report<D>], config: &AlignConfig) -> Alignment<D> {
+    let orientations = orientations();
     let (first_report, remaining_reports) = input.split_first().unwrap();
 
-    let mut known_beacons = HashSet::<Vector3<i32>>::new();
+    let mut known_beacons = HashSet::<Point<D>>::new();
     known_beacons.extend(first_report.iter());
 
     // NOTE: This does not contain fingerprints for all point-pairs (across report boundaries), only
@@ -127,102 +467,198 @@ fn solve_both(input: &Vec<Vec<Vector3<i32>>>) -> (usize, i32) {
 
     // Pre-compute fingerprints for each remaining report
     let mut reports_with_fingerprints = remaining_reports.iter()
+        .enumerate()
+        .map(|(index, report)| (index + 1, report, report_fingerprints(report)))
+        .collect::<Vec<_>>();
+
+    let mut scanners = vec![None; input.len()];
+    scanners[0] = Some(Pose::identity());
+
+    // Stop once a full pass over the remaining reports finds no match
+    while let Some((position, pose, transformed_report)) = {
+        reports_with_fingerprints.iter()
+            .enumerate()
+            .find_map(|(position, (_, report, fingerprints))| {
+                let known = (&known_beacons, &known_fingerprints);
+                find_match(known, report, fingerprints, &orientations, config)
+                    .map(|(pose, transformed_report)| (position, pose, transformed_report))
+            })
+    } {
+        let (index, _, _) = reports_with_fingerprints.swap_remove(position);
+        extend_fingerprints_from_report(&mut known_fingerprints, &transformed_report);
+        scanners[index] = Some(pose);
+        // With jitter, beacons close to a known beacon are the same beacon seen again. Both
+        // sightings can be off by the jitter, and so can the refined pose.
+        for p in transformed_report {
+            if find_nearby(&known_beacons, &p, 3 * config.jitter).is_none() {
+                known_beacons.insert(p);
+            }
+        }
+    }
+
+    Alignment { beacons: known_beacons, scanners }
+}
+
+/// Finds the connected components of the graph of reports that overlap each other
+fn overlap_components<const D: usize>(
+    input: &[Report<D>],
+    config: &AlignConfig,
+) -> Vec<Vec<usize>> {
+    let orientations = orientations();
+    let reports = input.iter()
         .map(|report| {
-            let fingerprints = report.iter()
-                .tuple_combinations::<(_, _)>()
-                .map(|(p1, p2)| {
-                    (fingerprint(p1, p2), [p1, p2])
-                })
-                .collect::<Vec<_>>();
-            (report, fingerprints)
+            let beacons = report.iter().copied().collect::<HashSet<_>>();
+            let mut fingerprints = HashMap::new();
+            extend_fingerprints_from_report(&mut fingerprints, report);
+            (report, beacons, fingerprints, report_fingerprints(report))
         })
         .collect::<Vec<_>>();
 
-    let mut scanners = vec![[0, 0, 0].into()];
-
-    while !reports_with_fingerprints.is_empty() {
-        for (index, (report, fingerprints)) in reports_with_fingerprints.iter().enumerate() {
-            if let Some((scanner, transformed_report)) = {
-                find_match(&known_beacons, &known_fingerprints, report, fingerprints)
-            } {
-                extend_fingerprints_from_report(&mut known_fingerprints, &transformed_report);
-                scanners.push(scanner);
-                known_beacons.extend(transformed_report.into_iter());
-                reports_with_fingerprints.swap_remove(index);
-                break;
-            }
+    let mut neighbors = vec![vec![]; input.len()];
+    for ((i, (_, beacons, fingerprints, _)), (j, (report, _, _, report_fingerprints))) in {
+        reports.iter().enumerate().tuple_combinations()
+    } {
+        let known = (beacons, fingerprints);
+        if find_match(known, report, report_fingerprints, &orientations, config).is_some() {
+            neighbors[i].push(j);
+            neighbors[j].push(i);
         }
     }
 
-    let scanner_range = scanners.into_iter()
-        .tuple_combinations::<(_, _)>()
-        .map(|(s1, s2)| norm_l1(&(s1 - s2)))
-        .max()
-        .unwrap();
+    let mut components = vec![];
+    let mut visited = vec![false; input.len()];
+    for start in 0..input.len() {
+        if visited[start] {
+            continue;
+        }
+        visited[start] = true;
 
-    (
-        // Part 1
-        known_beacons.len(),
-        // Part 2
-        scanner_range
-    )
+        let mut component = vec![];
+        let mut stack = vec![start];
+        while let Some(index) = stack.pop() {
+            component.push(index);
+            for &neighbor in &neighbors[index] {
+                if !visited[neighbor] {
+                    visited[neighbor] = true;
+                    stack.push(neighbor);
+                }
+            }
+        }
+        component.sort_unstable();
+        components.push(component);
+    }
+    components
 }
 
-fn find_match(
-    known_beacons: &HashSet<Vector3<i32>>,
-    known_fingerprints: &HashMap<Fingerprint, Vec<[Vector3<i32>; 2]>>,
-    report: &Vec<Vector3<i32>>,
-    report_fingerprints: &Vec<(Fingerprint, [&Vector3<i32>; 2])>,
-) -> Option<(Vector3<i32>, Vec<Vector3<i32>>)> {
+/// Beacons found so far, and the fingerprints of pairs of them
+type Known<'a, const D: usize> = (
+    &'a HashSet<Point<D>>,
+    &'a HashMap<Fingerprint, Vec<[Point<D>; 2]>>,
+);
+
+fn find_match<const D: usize>(
+    (known_beacons, known_fingerprints): Known<'_, D>,
+    report: &Report<D>,
+    report_fingerprints: &Vec<(Fingerprint, [&Point<D>; 2])>,
+    orientations: &[Rotation<D>],
+    config: &AlignConfig,
+) -> Option<(Pose<D>, Report<D>)> {
     let matching_fingerprints = report_fingerprints
         .iter()
-        .filter(|(f, _)| {
-            known_fingerprints.contains_key(f)
+        .map(|&(f, report_pair)| {
+            let known_pairs = config.nearby_fingerprints(f, D)
+                .filter_map(|f| known_fingerprints.get(&f))
+                .flatten()
+                .collect::<Vec<_>>();
+            (report_pair, known_pairs)
         })
+        .filter(|(_, known_pairs)| !known_pairs.is_empty())
         .collect::<Vec<_>>();
 
-    // At least (12 choose 2) fingerprints should match
-    if matching_fingerprints.len() < 66 {
+    // Every pair of overlapping beacons should have a matching fingerprint
+    let min_inliers = config.min_inliers();
+    if matching_fingerprints.len() < min_inliers * min_inliers.saturating_sub(1) / 2 {
         return None;
     }
 
-    for (f, report_pair) in matching_fingerprints {
-        for known_pair in known_fingerprints.get(&f).unwrap() {
-            // ASSUMPTION: Order of beacons in input is invariant between reports. So if beacon A
-            // appears before beacon B in report 1, so should it in other reports. Without this
-            // assumption we'd need to iterator through the cartesian product of report_pair with
-            // known_pair for our pinned points.
-            let [kp1, kp2] = known_pair;
-            let [rp1, rp2] = *report_pair;
-
-            let supported_rotations = ROTATION_MATRICES.iter()
-                // This always returns 0 or 1 items, so it could be replaced with a find().
-                // But it's not really performance critical, so I'll leave it as is.
-                .filter(|&m| {
-                    kp1 - m * rp1 == kp2 - m * rp2
-                });
-
-            for m in supported_rotations {
-                let translation = kp1 - m * rp1;
-
-                let transformed_report = report
-                    .iter()
-                    .map(|p| m * p + translation)
-                    .collect::<Vec<_>>();
-
-                let mut num_matches = 0;
-                for transformed_beacon in &transformed_report {
-                    if known_beacons.contains(transformed_beacon) {
-                        num_matches += 1;
+    // Many pairs of beacons lead to the same alignment, so only check each one once
+    let mut tried_alignments = HashSet::new();
+    let mut best_match: Option<(usize, Pose<D>)> = None;
+
+    'search: for ([rp1, rp2], known_pairs) in matching_fingerprints {
+        for [kp1, kp2] in known_pairs {
+            // Beacons can be listed in any order, so the report pair can match the known pair
+            // either way round
+            for (rp1, rp2) in [(rp1, rp2), (rp2, rp1)] {
+                let supported_rotations = orientations.iter()
+                    .filter(|&m| {
+                        norm_lmax(&((kp1 - kp2) - m * (rp1 - rp2))) <= 2 * config.jitter
+                    });
+
+                for &rotation in supported_rotations {
+                    let pose = Pose { rotation, translation: kp1 - rotation * rp1 };
+                    if !tried_alignments.insert(pose) {
+                        continue;
+                    }
+
+                    let (num_inliers, pose) = score_pose(known_beacons, report, pose, config);
+                    if best_match.is_none_or(|(best_inliers, _)| num_inliers > best_inliers) {
+                        best_match = Some((num_inliers, pose));
                     }
 
-                    // ASSUMPTION: Only 3 matches are needed to determine overlap.
-                    if num_matches >= 3 {
-                        return Some((translation, transformed_report));
+                    // No other pose can do better if every beacon matches, and without noise
+                    // only the correct pose can match enough beacons
+                    let is_best = num_inliers == report.len();
+                    if is_best || (config.is_exact() && num_inliers >= min_inliers) {
+                        break 'search;
                     }
                 }
             }
         }
     }
-    None
+
+    let (num_inliers, pose) = best_match?;
+    if num_inliers < min_inliers {
+        return None;
+    }
+
+    let transformed_report = report
+        .iter()
+        .map(|p| pose.apply(p))
+        .collect::<Vec<_>>();
+    Some((pose, transformed_report))
+}
+
+/// Counts the beacons in the report that match a known beacon when transformed by the pose.
+/// With jitter, a pose estimated from a single pair of beacons can be slightly off, so it is
+/// first refined using every beacon that roughly matches.
+fn score_pose<const D: usize>(
+    known_beacons: &HashSet<Point<D>>,
+    report: &[Point<D>],
+    mut pose: Pose<D>,
+    config: &AlignConfig,
+) -> (usize, Pose<D>) {
+    let matches = |pose: &Pose<D>, radius: i32| {
+        report.iter()
+            .filter_map(|p| {
+                let transformed = pose.apply(p);
+                find_nearby(known_beacons, &transformed, radius).map(|q| q - transformed)
+            })
+            .collect::<Vec<_>>()
+    };
+
+    if config.jitter > 0 {
+        // Both the translation and the matched beacons can be off by twice the jitter
+        let offsets = matches(&pose, 4 * config.jitter);
+        if !offsets.is_empty() {
+            let median = |axis: usize| {
+                let mut values = offsets.iter().map(|offset| offset[axis]).collect::<Vec<_>>();
+                values.sort_unstable();
+                values[values.len() / 2]
+            };
+            pose.translation += Point::from_fn(|axis, _| median(axis));
+        }
+    }
+
+    (matches(&pose, 2 * config.jitter).len(), pose)
 }
@@ -1,7 +1,7 @@
 mod day01;
 mod day02;
 mod day03;
-mod day19;
+pub mod day19;
 mod day20;
 mod day21;
 mod day22;
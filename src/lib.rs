@@ -1,6 +1,6 @@
 #[macro_use]
 extern crate aoc_runner_derive;
 
-mod days;
+pub mod days;
 
 aoc_lib! { year = 2021 }